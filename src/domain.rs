@@ -1,154 +1,313 @@
-use nalgebra::Vector3;
+use core::f64::consts::PI;
+
+use nalgebra::{Matrix3, Vector3};
+
+use crate::mesh;
 
 pub struct Box {
-    pub position: Vector3<i32>,
     pub real: Vec<i32>,
     pub ghost: Vec<i32>,
+    // (mesh, triangle) pairs any sphere in the box could reach
+    pub triangles: Vec<(usize, usize)>,
     pub lo: Vector3<f64>,
     pub hi: Vector3<f64>,
 }
 
-impl Box {
-    pub fn is_position_in_box(&self, position: Vector3<f64>) -> bool {
-        if position[0] >= self.lo[0]
-            && position[0] < self.hi[0]
-            && position[1] >= self.lo[1]
-            && position[1] < self.hi[1]
-            && position[2] >= self.lo[2]
-            && position[2] < self.hi[2]
-        {
-            return true;
-        }
+// A planar wall closing the lower or upper end of an axis, which is then no longer periodic
+pub struct Wall {
+    pub axis: usize,
+    pub position: f64,
+    // 1 for a lower wall facing up the axis, -1 for an upper wall
+    pub normal: f64,
+    // Velocity given with VELOCITY, the base of every motion
+    pub prescribed_velocity: Vector3<f64>,
+    pub velocity: Vector3<f64>,
+    pub motion: WallMotion,
+    // Total travel, tangential travel is only a record as walls are infinite
+    pub displacement: Vector3<f64>,
+    // Index into the materials, for the contact properties
+    pub material: usize,
+    // Force of the spheres on the wall this step, and summed over the stress averaging interval
+    pub force: Vector3<f64>,
+    pub force_sum: Vector3<f64>,
+}
 
-        return false;
-    }
+pub enum WallMotion {
+    Constant,
+    // Adds a displacement of amplitude * sin(2 pi frequency time)
+    Oscillating {
+        amplitude: Vector3<f64>,
+        frequency: f64,
+    },
+    // Moves along the normal at gain * (stress - normal stress) to hold the normal stress
+    Servo {
+        stress: f64,
+        gain: f64,
+    },
+}
 
-    pub fn is_position_in_max_radius_enlarged_box(
-        &self,
-        position: Vector3<f64>,
-        max_radius: f64,
-    ) -> bool {
-        if position[0] >= self.lo[0] - max_radius
-            && position[0] <= self.hi[0] + max_radius
-            && position[1] >= self.lo[1] - max_radius
-            && position[1] <= self.hi[1] + max_radius
-            && position[2] >= self.lo[2] - max_radius
-            && position[2] <= self.hi[2] + max_radius
-        {
-            return true;
-        }
+impl Wall {
+    // Distance of a point in front of the wall, negative behind it
+    pub fn distance(&self, position: Vector3<f64>) -> f64 {
+        (position[self.axis] - self.position) * self.normal
+    }
 
-        return false;
+    pub fn normal_vector(&self) -> Vector3<f64> {
+        let mut normal = Vector3::zeros();
+        normal[self.axis] = self.normal;
+        normal
     }
 
-    pub fn _is_sphere_aabb_in_box(&self, position: Vector3<f64>, radius: f64) -> bool {
-        if self.is_position_in_box(position + Vector3::new(radius, radius, radius)) {
-            return true;
-        } else if self.is_position_in_box(position + Vector3::new(radius, radius, -radius)) {
-            return true;
-        } else if self.is_position_in_box(position + Vector3::new(radius, -radius, radius)) {
-            return true;
-        } else if self.is_position_in_box(position + Vector3::new(radius, -radius, -radius)) {
-            return true;
-        } else if self.is_position_in_box(position + Vector3::new(-radius, radius, radius)) {
-            return true;
-        } else if self.is_position_in_box(position + Vector3::new(-radius, radius, -radius)) {
-            return true;
-        } else if self.is_position_in_box(position + Vector3::new(-radius, -radius, radius)) {
-            return true;
-        } else if self.is_position_in_box(position + Vector3::new(-radius, -radius, -radius)) {
-            return true;
-        }
+    pub fn area(&self, domain: Vector3<f64>) -> f64 {
+        domain[(self.axis + 1) % 3] * domain[(self.axis + 2) % 3]
+    }
 
-        return false;
+    // Compressive stress of the spheres on the wall this step
+    pub fn normal_stress(&self, domain: Vector3<f64>) -> f64 {
+        -self.force.dot(&self.normal_vector()) / self.area(domain)
     }
 
-    pub fn is_sphere_aabb_in_radius_enlarged_box(
-        &self,
-        position: Vector3<f64>,
-        radius: f64,
-        max_radius: f64,
-    ) -> bool {
-        if self.is_position_in_max_radius_enlarged_box(
-            position + Vector3::new(radius, radius, radius),
-            max_radius,
-        ) {
-            return true;
-        } else if self.is_position_in_max_radius_enlarged_box(
-            position + Vector3::new(radius, radius, -radius),
-            max_radius,
-        ) {
-            return true;
-        } else if self.is_position_in_max_radius_enlarged_box(
-            position + Vector3::new(radius, -radius, radius),
-            max_radius,
-        ) {
-            return true;
-        } else if self.is_position_in_max_radius_enlarged_box(
-            position + Vector3::new(radius, -radius, -radius),
-            max_radius,
-        ) {
-            return true;
-        } else if self.is_position_in_max_radius_enlarged_box(
-            position + Vector3::new(-radius, radius, radius),
-            max_radius,
-        ) {
-            return true;
-        } else if self.is_position_in_max_radius_enlarged_box(
-            position + Vector3::new(-radius, radius, -radius),
-            max_radius,
-        ) {
-            return true;
-        } else if self.is_position_in_max_radius_enlarged_box(
-            position + Vector3::new(-radius, -radius, radius),
-            max_radius,
-        ) {
-            return true;
-        } else if self.is_position_in_max_radius_enlarged_box(
-            position + Vector3::new(-radius, -radius, -radius),
-            max_radius,
-        ) {
-            return true;
+    // Sets the velocity for the step starting at time and moves the wall
+    pub fn move_wall(&mut self, domain: Vector3<f64>, time: f64, dt: f64) {
+        self.velocity = self.prescribed_velocity;
+        match self.motion {
+            WallMotion::Constant => {}
+            WallMotion::Oscillating {
+                amplitude,
+                frequency,
+            } => {
+                // Taken at the middle of the step so the displacement follows the sine closely
+                let omega = 2.0 * PI * frequency;
+                self.velocity += amplitude * omega * (omega * (time + 0.5 * dt)).cos();
+            }
+            WallMotion::Servo { stress, gain } => {
+                self.velocity[self.axis] +=
+                    self.normal * gain * (stress - self.normal_stress(domain));
+            }
         }
 
-        return false;
+        self.displacement += self.velocity * dt;
+        self.position += self.velocity[self.axis] * dt;
     }
+}
 
-    pub fn is_periodic_sphere(
-        &self,
-        position: Vector3<f64>,
-        radius: f64,
-        max_radius: f64,
-        d_data: &DomainData,
-    ) -> bool {
-        if position[0] - radius <= 0.0 + max_radius {
-            return true;
-        }
-        if position[0] + radius >= d_data.domain[0] - max_radius {
-            return true;
-        }
-        if position[1] - radius <= 0.0 + max_radius {
-            return true;
-        }
-        if position[1] + radius >= d_data.domain[1] - max_radius {
-            return true;
-        }
-        if position[2] - radius <= 0.0 + max_radius {
-            return true;
-        }
-        if position[2] + radius >= d_data.domain[2] - max_radius {
-            return true;
-        }
+// Holds the normal stress on the gradient axis of a cell periodic along it, by straining the cell
+// along that axis at gain * (normal stress - stress), so the cell expands when the spheres push
+// harder than the target
+pub struct Barostat {
+    pub stress: f64,
+    pub gain: f64,
+}
 
-        return false;
-    }
+// Time dependence of the Lees-Edwards shear rate, timed from the SHEAR command that set it
+pub enum ShearSchedule {
+    // The LEB rate
+    Constant,
+    // Strain of amplitude * sin(2 pi frequency time)
+    Oscillating { amplitude: f64, frequency: f64 },
+    // Linear between two rates over duration, then held
+    Ramp { from: f64, to: f64, duration: f64 },
+    // One rate until time and another after
+    Step { before: f64, after: f64, time: f64 },
+    // The rate with its sign flipped every period
+    Reversal { rate: f64, period: f64 },
+}
+
+// Region spheres are poured or fed into at a steady rate during a run
+pub struct Inserter {
+    pub lo: Vector3<f64>,
+    pub hi: Vector3<f64>,
+    // Spheres per unit time
+    pub rate: f64,
+    // Material indexes with their relative weights
+    pub materials: Vec<(usize, f64)>,
+    // Radii drawn uniformly from this range in place of the material radius
+    pub radius_range: Option<(f64, f64)>,
+    pub velocity: Vector3<f64>,
+    // Spheres still to insert, None for no limit
+    pub remaining: Option<usize>,
+    // Spheres due but not inserted yet, carried between steps
+    pub due: f64,
+    // No free place was found, so the region waits until the spheres have moved
+    pub full: bool,
+}
+
+// Region deleting the spheres whose centres enter it, recording the mass taken out
+pub struct Outlet {
+    pub lo: Vector3<f64>,
+    pub hi: Vector3<f64>,
+    // Spheres and mass removed since the last report
+    pub count: usize,
+    pub mass: f64,
+    pub total_mass: f64,
+}
+
+// Half list of the sphere pairs within contact reach plus the skin, each pair once. It holds
+// until a sphere or a periodic image has moved half the skin since it was built
+pub struct NeighborList {
+    pub skin: f64,
+    pub pairs: Vec<(usize, usize)>,
+    // Positions and lattice vectors at the last build, emptied to force a rebuild when spheres
+    // are added or removed
+    pub positions: Vec<Vector3<f64>>,
+    pub lattice: Matrix3<f64>,
 }
 
-#[derive()]
 pub struct DomainData {
     pub(crate) domain: Vector3<f64>,
     pub(crate) domain_volume: f64,
     pub(crate) collision_boxes: Vector3<i32>,
     pub(crate) lees_edwards_boundary: f64,
+    // The Lees-Edwards rate shears the flow axis along the gradient axis
+    pub(crate) flow_axis: usize,
+    pub(crate) gradient_axis: usize,
+    // From VELOCITY_GRADIENT, with the Lees-Edwards rate in place of its flow gradient component
+    pub(crate) imposed_gradient: Matrix3<f64>,
+    // Off diagonal of the cell matrix, whose columns are the lattice vectors. Column j is how far
+    // the image across axis j is displaced along the other axes, the Lees-Edwards offset for a
+    // simple shear
+    pub(crate) tilt: Matrix3<f64>,
+    // Each lattice vector is only tilted along the axes before its own in this order
+    pub(crate) cell_order: [usize; 3],
     pub(crate) g_data: Vec<Vec<Vec<Box>>>,
+    pub(crate) walls: Vec<Wall>,
+    pub(crate) meshes: Vec<mesh::Mesh>,
+    pub(crate) inserters: Vec<Inserter>,
+    pub(crate) outlets: Vec<Outlet>,
+    pub(crate) neighbors: NeighborList,
+    // Cycles between reorderings of the spheres along a Morton curve, 0 for never
+    pub(crate) sort_interval: i32,
+    // Axes without walls, which wrap around
+    pub(crate) periodic: Vector3<bool>,
+    pub(crate) barostat: Option<Barostat>,
+    pub(crate) shear_schedule: ShearSchedule,
+    // Simulated time the shear schedule started at
+    pub(crate) shear_start: f64,
+}
+
+// A unit cell, reshaped by START and the other commands of the input file
+impl Default for DomainData {
+    fn default() -> Self {
+        DomainData {
+            domain: Vector3::new(1.0, 1.0, 1.0),
+            domain_volume: 1.0,
+            collision_boxes: Vector3::new(1, 1, 1),
+            g_data: Vec::new(),
+            lees_edwards_boundary: 1.0,
+            flow_axis: 0,
+            gradient_axis: 1,
+            imposed_gradient: Matrix3::zeros(),
+            tilt: Matrix3::zeros(),
+            cell_order: [0, 1, 2],
+            walls: Vec::new(),
+            meshes: Vec::new(),
+            inserters: Vec::new(),
+            outlets: Vec::new(),
+            neighbors: NeighborList {
+                skin: 0.0,
+                pairs: Vec::new(),
+                positions: Vec::new(),
+                lattice: Matrix3::zeros(),
+            },
+            sort_interval: 0,
+            periodic: Vector3::new(true, true, true),
+            barostat: None,
+            shear_schedule: ShearSchedule::Constant,
+            shear_start: 0.0,
+        }
+    }
+}
+
+impl DomainData {
+    // Velocity gradient this step, row i column j is the derivative of velocity i along axis j
+    pub fn velocity_gradient(&self) -> Matrix3<f64> {
+        let mut gradient = self.imposed_gradient;
+        gradient[(self.flow_axis, self.gradient_axis)] = self.lees_edwards_boundary;
+        gradient
+    }
+
+    // Cell edge along an axis with the tilt of its image
+    pub fn lattice_vector(&self, axis: usize) -> Vector3<f64> {
+        let mut vector = self.tilt.column(axis).into_owned();
+        vector[axis] = self.domain[axis];
+        vector
+    }
+
+    // Velocity of the image across an axis relative to the sphere. Only the shear is carried by
+    // the velocities, stretching moves the positions
+    pub fn image_velocity(&self, axis: usize) -> Vector3<f64> {
+        let mut shear = self.velocity_gradient();
+        shear.fill_diagonal(0.0);
+        shear * self.lattice_vector(axis)
+    }
+
+    // A separation brought to its nearest periodic image
+    pub fn nearest_image(&self, mut delta: Vector3<f64>) -> Vector3<f64> {
+        for &axis in self.cell_order.iter().rev() {
+            if !self.periodic[axis] {
+                continue;
+            }
+            // A tilted image can leave the flow component more than one length out
+            delta -= (delta[axis] / self.domain[axis]).round() * self.lattice_vector(axis);
+        }
+        delta
+    }
+
+    // An order of the axes with every shear flow axis before its gradient axis, so the cell
+    // matrix stays triangular in it. None when the shears go round in a loop. The Lees-Edwards
+    // pair counts even at a zero rate, as a SHEAR schedule can start it later
+    pub fn shear_order(&self) -> Option<[usize; 3]> {
+        let mut gradient = self.imposed_gradient;
+        gradient[(self.flow_axis, self.gradient_axis)] = 1.0;
+        [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ]
+        .into_iter()
+        .find(|order| {
+            (0..3).all(|later| {
+                (0..later).all(|earlier| gradient[(order[later], order[earlier])] == 0.0)
+            })
+        })
+    }
+
+    // Lees-Edwards shear rate at a simulated time
+    pub fn shear_rate(&self, time: f64) -> f64 {
+        let elapsed = time - self.shear_start;
+        match self.shear_schedule {
+            ShearSchedule::Constant => self.lees_edwards_boundary,
+            ShearSchedule::Oscillating {
+                amplitude,
+                frequency,
+            } => {
+                let omega = 2.0 * PI * frequency;
+                amplitude * omega * (omega * elapsed).cos()
+            }
+            ShearSchedule::Ramp { from, to, duration } => {
+                from + (to - from) * (elapsed / duration).min(1.0)
+            }
+            ShearSchedule::Step {
+                before,
+                after,
+                time,
+            } => {
+                if elapsed < time {
+                    before
+                } else {
+                    after
+                }
+            }
+            ShearSchedule::Reversal { rate, period } => {
+                if (elapsed / period).floor() as i64 % 2 == 0 {
+                    rate
+                } else {
+                    -rate
+                }
+            }
+        }
+    }
 }
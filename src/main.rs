@@ -1,10 +1,9 @@
 mod simulation;
-use nalgebra::Vector3;
+use nalgebra::{Matrix3, Vector3};
 
 use ex::fs::File;
 use rand::prelude::*;
 use std::{
-    collections::HashMap,
     env,
     f64::consts::PI,
     io::{prelude::*, BufReader},
@@ -12,6 +11,7 @@ use std::{
 };
 
 mod domain;
+mod mesh;
 mod sphere;
 
 fn lines_from_file(filename: String) -> Vec<String> {
@@ -47,32 +47,8 @@ fn main() {
     let filename = args.remove(1);
     let lines = lines_from_file(filename);
 
-    let mut p_data = sphere::ParticleData {
-        max_radius: 0.0,
-        radius: Vec::<f64>::new(),
-        mass: Vec::<f64>::new(),
-        youngs_mod: Vec::<f64>::new(),
-        poisson_ratio: Vec::<f64>::new(),
-        density: Vec::<f64>::new(),
-        position: Vec::<Vector3<f64>>::new(),
-        velocity: Vec::<Vector3<f64>>::new(),
-        force: Vec::<Vector3<f64>>::new(),
-        is_collision: Vec::<bool>::new(),
-        materials: Vec::<sphere::Material>::new(),
-        sphere_material: Vec::<usize>::new(),
-        sphere_material_map: HashMap::new(),
-        restitution_coefficient: 0.95,
-        beta: 0.0,
-        friction: 0.1,
-        volume_fraction: 0.0,
-    };
-    let mut d_data = domain::DomainData {
-        domain: Vector3::new(1.0, 1.0, 1.0),
-        domain_volume: 1.0,
-        collision_boxes: Vector3::new(1, 1, 1),
-        g_data: Vec::new(),
-        lees_edwards_boundary: 1.0,
-    };
+    let mut p_data = sphere::ParticleData::default();
+    let mut d_data = domain::DomainData::default();
 
     let mut argument = 0;
     for line in lines {
@@ -82,11 +58,9 @@ fn main() {
 
         let results: Vec<&str> = line.split_whitespace().collect();
 
-        if argument == 0 {
-            if &results[0][0..3] != "STA" {
-                println!("First Command must be START");
-                process::exit(1);
-            }
+        if argument == 0 && &results[0][0..3] != "STA" {
+            println!("First Command must be START");
+            process::exit(1);
         }
 
         match &results[0][0..3] {
@@ -120,9 +94,9 @@ fn main() {
                             );
 
                             let the_box = domain::Box {
-                                position: Vector3::new(i, j, k),
                                 real: Vec::<i32>::new(),
                                 ghost: Vec::<i32>::new(),
+                                triangles: Vec::new(),
                                 lo: Vector3::new(
                                     i as f64 * len[0],
                                     j as f64 * len[1],
@@ -143,29 +117,238 @@ fn main() {
             "DAM" => {
                 println!("{}", line);
                 p_data.restitution_coefficient = results[1].parse::<f64>().unwrap();
-
-                let log_e = p_data.restitution_coefficient.ln();
-                let beta = -log_e / (PI * PI + log_e * log_e).sqrt();
-
-                p_data.beta = beta;
+                if p_data.restitution_coefficient <= 0.0 || p_data.restitution_coefficient > 1.0 {
+                    println!("DAMPING restitution coefficient must be above 0 and at most 1");
+                    process::exit(1);
+                }
+                p_data.beta = simulation::restitution_to_beta(p_data.restitution_coefficient);
+            }
+            "RES" => {
+                println!("{}", line);
+                p_data.restitution_model = match results[1] {
+                    "CONSTANT" => sphere::RestitutionModel::Constant,
+                    "VISCOELASTIC" => sphere::RestitutionModel::Viscoelastic {
+                        dissipative_constant: results[2].parse::<f64>().unwrap(),
+                    },
+                    "TABLE" => {
+                        // Impact velocity and restitution pairs in increasing velocity
+                        let values: Vec<f64> = results[2..]
+                            .iter()
+                            .map(|value| value.parse::<f64>().unwrap())
+                            .collect();
+                        if values.is_empty() || !values.len().is_multiple_of(2) {
+                            println!("Restitution table needs velocity restitution pairs");
+                            process::exit(1);
+                        }
+                        let velocities: Vec<f64> = values.iter().step_by(2).copied().collect();
+                        if velocities.windows(2).any(|pair| pair[1] <= pair[0]) {
+                            println!("Restitution table velocities must be increasing");
+                            process::exit(1);
+                        }
+                        let restitution: Vec<f64> =
+                            values.iter().skip(1).step_by(2).copied().collect();
+                        if restitution.iter().any(|&e| e <= 0.0 || e > 1.0) {
+                            println!("Restitution table values must be above 0 and at most 1");
+                            process::exit(1);
+                        }
+                        sphere::RestitutionModel::Tabulated {
+                            velocities,
+                            restitution,
+                        }
+                    }
+                    _ => {
+                        println!("Restitution model must be CONSTANT, VISCOELASTIC or TABLE");
+                        process::exit(1);
+                    }
+                };
+            }
+            "FRI" => {
+                println!("{}", line);
+                p_data.friction = results[1].parse::<f64>().unwrap();
+            }
+            "CON" => {
+                println!("{}", line);
+                p_data.contact_law = match results[1] {
+                    "HERTZ" => sphere::ContactLaw::Hertz,
+                    "HERTZ_MINDLIN" => sphere::ContactLaw::HertzMindlin,
+                    "THORNTON" => sphere::ContactLaw::Thornton,
+                    "HOOKE" => {
+                        let normal_stiffness = results[2].parse::<f64>().unwrap();
+                        // Default tangential to normal stiffness ratio of 2/7
+                        let tangential_stiffness = match results.get(3) {
+                            Some(value) => value.parse::<f64>().unwrap(),
+                            None => 2.0 / 7.0 * normal_stiffness,
+                        };
+                        sphere::ContactLaw::Hooke {
+                            normal_stiffness,
+                            tangential_stiffness,
+                        }
+                    }
+                    _ => {
+                        println!("Contact law must be HERTZ, HERTZ_MINDLIN, THORNTON or HOOKE");
+                        process::exit(1);
+                    }
+                };
+            }
+            "ROL" => {
+                println!("{}", line);
+                p_data.rolling_resistance = match results[1] {
+                    "CONSTANT" => sphere::RollingResistance::ConstantTorque,
+                    "SPRING" => sphere::RollingResistance::SpringDashpot,
+                    _ => {
+                        println!("Rolling resistance must be CONSTANT or SPRING");
+                        process::exit(1);
+                    }
+                };
+                if results.len() > 2 {
+                    p_data.rolling_damping = results[2].parse::<f64>().unwrap();
+                }
+            }
+            "COH" => {
+                println!("{}", line);
+                p_data.cohesion_model = match results[1] {
+                    "JKR" => sphere::CohesionModel::Jkr,
+                    "DMT" => sphere::CohesionModel::Dmt,
+                    "LINEAR" => sphere::CohesionModel::Linear,
+                    _ => {
+                        println!("Cohesion model must be JKR, DMT or LINEAR");
+                        process::exit(1);
+                    }
+                };
+            }
+            "CAP" => {
+                println!("{}", line);
+                p_data.surface_tension = results[1].parse::<f64>().unwrap();
+            }
+            "THR" => {
+                println!("{}", line);
+                let threads = results[1].parse::<usize>().unwrap();
+                if let Err(err) = rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build_global()
+                {
+                    println!("THREADS must be given once, before the first CYC: {}", err);
+                    process::exit(1);
+                }
+            }
+            "SKI" => {
+                println!("{}", line);
+                d_data.neighbors.skin = results[1].parse::<f64>().unwrap();
+                if d_data.neighbors.skin < 0.0 {
+                    println!("SKIN must not be negative");
+                    process::exit(1);
+                }
+            }
+            "SOR" => {
+                println!("{}", line);
+                d_data.sort_interval = results[1].parse::<i32>().unwrap();
+                if d_data.sort_interval < 0 {
+                    println!("SORT interval must not be negative");
+                    process::exit(1);
+                }
             }
             "LEB" => {
                 println!("{}", line);
                 d_data.lees_edwards_boundary = results[1].parse::<f64>().unwrap();
+
+                for option in results[2..].chunks(2) {
+                    if option.len() < 2 {
+                        println!("LEB option {} is missing an axis", option[0]);
+                        process::exit(1);
+                    }
+                    match option[0] {
+                        "FLOW" => d_data.flow_axis = axis_index(option[1]),
+                        "GRADIENT" => d_data.gradient_axis = axis_index(option[1]),
+                        _ => {
+                            println!("Unknown LEB option {}", option[0]);
+                            process::exit(1);
+                        }
+                    }
+                }
+                if d_data.flow_axis == d_data.gradient_axis {
+                    println!("Lees-Edwards flow and gradient axes must differ");
+                    process::exit(1);
+                }
+            }
+            "VEL" => {
+                println!("{}", line);
+                if results.len() < 10 {
+                    println!("VELOCITY_GRADIENT needs the nine components row by row");
+                    process::exit(1);
+                }
+                let values: Vec<f64> = results[1..10]
+                    .iter()
+                    .map(|value| value.parse::<f64>().unwrap())
+                    .collect();
+                // Row i column j is the derivative of velocity i along axis j
+                let gradient = Matrix3::from_row_slice(&values);
+
+                d_data.imposed_gradient = gradient;
+
+                // The first shear component is the Lees-Edwards one, which SHEAR schedules
+                let shear = (0..3)
+                    .flat_map(|row| (0..3).map(move |column| (row, column)))
+                    .find(|&(row, column)| row != column && gradient[(row, column)] != 0.0);
+                match shear {
+                    Some((flow, gradient_axis)) => {
+                        d_data.flow_axis = flow;
+                        d_data.gradient_axis = gradient_axis;
+                        d_data.lees_edwards_boundary = gradient[(flow, gradient_axis)];
+                    }
+                    None => d_data.lees_edwards_boundary = 0.0,
+                }
+            }
+            "BAR" => {
+                println!("{}", line);
+                d_data.barostat = Some(domain::Barostat {
+                    stress: results[1].parse::<f64>().unwrap(),
+                    gain: results[2].parse::<f64>().unwrap(),
+                });
             }
             "MAT" => {
                 println!("{}", line);
 
-                let material = sphere::Material {
-                    radius: results[2].parse::<f64>().unwrap(),
-                    mass: results[3].parse::<f64>().unwrap() * PI * 4.0 / 3.0
-                        * results[2].parse::<f64>().unwrap().powi(3),
+                let radius = results[2].parse::<f64>().unwrap();
+                let mass = results[3].parse::<f64>().unwrap() * PI * 4.0 / 3.0 * radius.powi(3);
+
+                let mut material = sphere::Material {
+                    radius,
+                    mass,
+                    moment_of_inertia: 0.4 * mass * radius * radius,
                     youngs_mod: results[4].parse::<f64>().unwrap(),
                     poisson_ratio: results[5].parse::<f64>().unwrap(),
                     density: results[3].parse::<f64>().unwrap(),
+                    rolling_friction: 0.0,
+                    twisting_friction: 0.0,
+                    surface_energy: 0.0,
+                    liquid_volume: 0.0,
+                    contact_angle: 0.0,
+                    yield_pressure: 0.0,
                     id: results[1].parse::<i32>().unwrap(),
                 };
 
+                // Optional material properties are given as name value pairs after the
+                // required ones
+                for property in results[6..].chunks(2) {
+                    if property.len() < 2 {
+                        println!("Material property {} is missing a value", property[0]);
+                        process::exit(1);
+                    }
+                    let value = property[1].parse::<f64>().unwrap();
+                    match property[0] {
+                        "ROLLING" => material.rolling_friction = value,
+                        "TWISTING" => material.twisting_friction = value,
+                        "SURFACE" => material.surface_energy = value,
+                        "LIQUID" => material.liquid_volume = value,
+                        "ANGLE" => material.contact_angle = value.to_radians(),
+                        "YIELD" => material.yield_pressure = value,
+                        _ => {
+                            println!("Unknown material property {}", property[0]);
+                            process::exit(1);
+                        }
+                    }
+                }
+
                 p_data.materials.push(material);
 
                 println!("Material Loaded: {:?} ", p_data.materials.last());
@@ -184,19 +367,10 @@ fn main() {
 
                 let num_particles: i32 = results[1].parse::<i32>().unwrap();
 
-                for (_i, material) in p_data.materials.iter().enumerate() {
+                for material in p_data.materials.clone() {
                     if material.id == results[2].parse::<i32>().unwrap() {
                         println!("Generating particles with Material: {:?}", material);
                         for _j in 0..num_particles {
-                            p_data.sphere_material.push(material.id as usize);
-
-                            p_data.radius.push(material.radius);
-
-                            p_data.mass.push(material.mass);
-                            p_data.density.push(material.density);
-                            p_data.youngs_mod.push(material.youngs_mod);
-                            p_data.poisson_ratio.push(material.poisson_ratio);
-
                             let x: f64 = rng.gen::<f64>();
                             let y: f64 = rng.gen::<f64>();
                             let z: f64 = rng.gen::<f64>();
@@ -205,19 +379,15 @@ fn main() {
                             let vy: f64 = rng.gen::<f64>();
                             let vz: f64 = rng.gen::<f64>();
 
-                            p_data.position.push(Vector3::new(
-                                d_data.domain.x * x,
-                                d_data.domain.y * y,
-                                d_data.domain.z * z,
-                            ));
-                            p_data.velocity.push(Vector3::new(
-                                vx * 0.1 - 0.05,
-                                vy * 0.1 - 0.05,
-                                vz * 0.1 - 0.05,
-                            ));
-                            p_data.force.push(Vector3::new(0.0, 0.0, 0.0));
-
-                            p_data.is_collision.push(false);
+                            p_data.push_particle(
+                                &material,
+                                Vector3::new(
+                                    d_data.domain.x * x,
+                                    d_data.domain.y * y,
+                                    d_data.domain.z * z,
+                                ),
+                                Vector3::new(vx * 0.1 - 0.05, vy * 0.1 - 0.05, vz * 0.1 - 0.05),
+                            );
                         }
                     }
                 }
@@ -230,59 +400,244 @@ fn main() {
             "FOR" => {
                 println!("{}", line);
 
-                for (_i, material) in p_data.materials.iter().enumerate() {
+                for material in p_data.materials.clone() {
                     if material.id == results[1].parse::<i32>().unwrap() {
                         println!("Generating two particle for force check");
 
-                        p_data.sphere_material.push(material.id as usize);
-
-                        p_data.radius.push(material.radius);
-
-                        p_data.mass.push(material.mass);
-                        p_data.density.push(material.density);
-                        p_data.youngs_mod.push(material.youngs_mod);
-                        p_data.poisson_ratio.push(material.poisson_ratio);
-
-                        p_data.position.push(Vector3::new(
-                            d_data.domain.x * 0.4,
-                            d_data.domain.y * 0.5,
-                            d_data.domain.z * 0.5,
-                        ));
-                        p_data.velocity.push(Vector3::new(1.0, 0.0, 0.0));
-                        p_data.force.push(Vector3::new(0.0, 0.0, 0.0));
+                        p_data.push_particle(
+                            &material,
+                            Vector3::new(
+                                d_data.domain.x * 0.4,
+                                d_data.domain.y * 0.5,
+                                d_data.domain.z * 0.5,
+                            ),
+                            Vector3::new(1.0, 0.0, 0.0),
+                        );
+
+                        p_data.push_particle(
+                            &material,
+                            Vector3::new(
+                                d_data.domain.x * 0.6,
+                                d_data.domain.y * 0.5,
+                                d_data.domain.z * 0.5,
+                            ),
+                            Vector3::new(-1.0, 0.0, 0.0),
+                        );
+                    }
+                }
+            }
+            "PAI" => {
+                println!("{}", line);
+                command_stack.push(line);
+            }
+            "REL" => {
+                println!("{}", line);
+                command_stack.push(line);
+            }
+            "BON" => {
+                println!("{}", line);
+                command_stack.push(line);
+            }
+            "SHE" => {
+                println!("{}", line);
+                command_stack.push(line);
+            }
+            "INS" => {
+                println!("{}", line);
+                command_stack.push(line);
+            }
+            "OUT" => {
+                println!("{}", line);
+                command_stack.push(line);
+            }
+            "CYC" => {
+                println!("{}", line);
+                command_stack.push(line);
+            }
+            "WAL" => {
+                println!("{}", line);
 
-                        p_data.is_collision.push(false);
+                let axis = axis_index(results[1]);
+                let (position, normal) = match results[2] {
+                    "LOWER" => (0.0, 1.0),
+                    "UPPER" => (d_data.domain[axis], -1.0),
+                    _ => {
+                        println!("Wall side must be LOWER or UPPER");
+                        process::exit(1);
+                    }
+                };
+                let id = results[3].parse::<i32>().unwrap();
+                let material = match p_data.materials.iter().position(|m| m.id == id) {
+                    Some(index) => index,
+                    None => {
+                        println!("WALL uses unknown material {}", id);
+                        process::exit(1);
+                    }
+                };
 
-                        p_data.sphere_material.push(material.id as usize);
+                // Optional motions, each keyword followed by its values
+                let mut velocity = Vector3::zeros();
+                let mut motion = domain::WallMotion::Constant;
+                let mut index = 4;
+                while index < results.len() {
+                    let count = match results[index] {
+                        "VELOCITY" => 3,
+                        "OSCILLATE" => 4,
+                        "SERVO" => 2,
+                        _ => {
+                            println!("Unknown wall motion {}", results[index]);
+                            process::exit(1);
+                        }
+                    };
+                    if index + count >= results.len() {
+                        println!("Wall motion {} needs {} values", results[index], count);
+                        process::exit(1);
+                    }
+                    let values: Vec<f64> = results[index + 1..=index + count]
+                        .iter()
+                        .map(|value| value.parse::<f64>().unwrap())
+                        .collect();
+
+                    match results[index] {
+                        "VELOCITY" => velocity = Vector3::new(values[0], values[1], values[2]),
+                        "OSCILLATE" => {
+                            motion = domain::WallMotion::Oscillating {
+                                amplitude: Vector3::new(values[0], values[1], values[2]),
+                                frequency: values[3],
+                            }
+                        }
+                        _ => {
+                            motion = domain::WallMotion::Servo {
+                                stress: values[0],
+                                gain: values[1],
+                            }
+                        }
+                    }
+                    index += count + 1;
+                }
 
-                        p_data.radius.push(material.radius);
+                d_data.periodic[axis] = false;
+                d_data.walls.push(domain::Wall {
+                    axis,
+                    position,
+                    normal,
+                    prescribed_velocity: velocity,
+                    velocity,
+                    motion,
+                    displacement: Vector3::zeros(),
+                    material,
+                    force: Vector3::zeros(),
+                    force_sum: Vector3::zeros(),
+                });
+            }
+            "STL" => {
+                println!("{}", line);
 
-                        p_data.mass.push(material.mass);
-                        p_data.density.push(material.density);
-                        p_data.youngs_mod.push(material.youngs_mod);
-                        p_data.poisson_ratio.push(material.poisson_ratio);
+                let id = results[2].parse::<i32>().unwrap();
+                let material = match p_data.materials.iter().position(|m| m.id == id) {
+                    Some(index) => index,
+                    None => {
+                        println!("STL uses unknown material {}", id);
+                        process::exit(1);
+                    }
+                };
 
-                        p_data.position.push(Vector3::new(
-                            d_data.domain.x * 0.6,
-                            d_data.domain.y * 0.5,
-                            d_data.domain.z * 0.5,
-                        ));
-                        p_data.velocity.push(Vector3::new(-1.0, 0.0, 0.0));
-                        p_data.force.push(Vector3::new(0.0, 0.0, 0.0));
+                let mut triangles = mesh::read_stl(results[1]);
+
+                // Optional placement and motion, each keyword followed by its values
+                let mut scale = 1.0;
+                let mut offset = Vector3::zeros();
+                let mut velocity = Vector3::zeros();
+                let mut center = Vector3::zeros();
+                let mut angular_velocity = Vector3::zeros();
+                let mut index = 3;
+                while index < results.len() {
+                    let count = match results[index] {
+                        "SCALE" => 1,
+                        "OFFSET" | "VELOCITY" => 3,
+                        "ROTATE" => 6,
+                        _ => {
+                            println!("Unknown STL property {}", results[index]);
+                            process::exit(1);
+                        }
+                    };
+                    if index + count >= results.len() {
+                        println!("STL property {} needs {} values", results[index], count);
+                        process::exit(1);
+                    }
+                    let values: Vec<f64> = results[index + 1..=index + count]
+                        .iter()
+                        .map(|value| value.parse::<f64>().unwrap())
+                        .collect();
+
+                    match results[index] {
+                        "SCALE" => scale = values[0],
+                        "OFFSET" => offset = Vector3::new(values[0], values[1], values[2]),
+                        "VELOCITY" => velocity = Vector3::new(values[0], values[1], values[2]),
+                        _ => {
+                            center = Vector3::new(values[0], values[1], values[2]);
+                            angular_velocity = Vector3::new(values[3], values[4], values[5]);
+                        }
+                    }
+                    index += count + 1;
+                }
 
-                        p_data.is_collision.push(false);
+                for triangle in &mut triangles {
+                    for vertex in triangle.iter_mut() {
+                        *vertex = *vertex * scale + offset;
                     }
                 }
+                println!("Loaded {} triangles", triangles.len());
+
+                d_data.meshes.push(mesh::Mesh {
+                    triangles,
+                    material,
+                    velocity,
+                    center,
+                    angular_velocity,
+                    force: Vector3::zeros(),
+                    torque: Vector3::zeros(),
+                });
             }
-            "REL" => {
+            "GRA" => {
                 println!("{}", line);
-                command_stack.push(line);
+                p_data.body_forces.push(sphere::BodyForce::Uniform {
+                    acceleration: Vector3::new(
+                        results[1].parse::<f64>().unwrap(),
+                        results[2].parse::<f64>().unwrap(),
+                        results[3].parse::<f64>().unwrap(),
+                    ),
+                });
             }
-            "CYC" => {
+            "BOD" => {
                 println!("{}", line);
-                command_stack.push(line);
+                let values: Vec<f64> = results[2..]
+                    .iter()
+                    .map(|value| value.parse::<f64>().unwrap())
+                    .collect();
+                let body_force = match (results[1], values.len()) {
+                    ("UNIFORM", 3) => sphere::BodyForce::Uniform {
+                        acceleration: Vector3::new(values[0], values[1], values[2]),
+                    },
+                    ("GRADIENT", 12) => sphere::BodyForce::Gradient {
+                        acceleration: Vector3::new(values[0], values[1], values[2]),
+                        gradient: Matrix3::from_row_slice(&values[3..12]),
+                    },
+                    ("OSCILLATING", 4) => sphere::BodyForce::Oscillating {
+                        acceleration: Vector3::new(values[0], values[1], values[2]),
+                        frequency: values[3],
+                    },
+                    _ => {
+                        println!("Body force must be UNIFORM a_x a_y a_z, GRADIENT a_x a_y a_z followed by the 9 gradient entries row by row, or OSCILLATING a_x a_y a_z frequency");
+                        process::exit(1);
+                    }
+                };
+                p_data.body_forces.push(body_force);
+            }
+            _ => {
+                println!("Unknown command {}", results[0]);
+                process::exit(1);
             }
-            _ => {}
         }
 
         argument += 1;
@@ -290,3 +645,15 @@ fn main() {
 
     simulation::handle_commands(command_stack, d_data, p_data);
 }
+
+fn axis_index(axis: &str) -> usize {
+    match axis {
+        "X" => 0,
+        "Y" => 1,
+        "Z" => 2,
+        _ => {
+            println!("Axis must be X, Y or Z, not {}", axis);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use ex::fs;
+use nalgebra::{Rotation3, Vector3};
+use std::process;
+
+// A triangle mesh from an STL file, moving as a rigid body. Spheres touch its faces, edges and
+// vertices with the wall contact law
+pub struct Mesh {
+    pub triangles: Vec<[Vector3<f64>; 3]>,
+    // Index into the materials, for the contact properties
+    pub material: usize,
+    pub velocity: Vector3<f64>,
+    // The mesh rotates about this point, which moves with the velocity
+    pub center: Vector3<f64>,
+    pub angular_velocity: Vector3<f64>,
+    // Force and torque about the center from the spheres, summed over the stress averaging
+    // interval
+    pub force: Vector3<f64>,
+    pub torque: Vector3<f64>,
+}
+
+impl Mesh {
+    pub fn velocity_at(&self, point: Vector3<f64>) -> Vector3<f64> {
+        self.velocity + self.angular_velocity.cross(&(point - self.center))
+    }
+
+    pub fn move_mesh(&mut self, dt: f64) {
+        let translation = self.velocity * dt;
+        let rotation = Rotation3::new(self.angular_velocity * dt);
+
+        for triangle in &mut self.triangles {
+            for vertex in triangle.iter_mut() {
+                *vertex = self.center + translation + rotation * (*vertex - self.center);
+            }
+        }
+        self.center += translation;
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.velocity != Vector3::zeros() || self.angular_velocity != Vector3::zeros()
+    }
+
+    // Bounding box of a triangle
+    pub fn triangle_bounds(&self, triangle: usize) -> (Vector3<f64>, Vector3<f64>) {
+        let [a, b, c] = self.triangles[triangle];
+        (a.inf(&b).inf(&c), a.sup(&b).sup(&c))
+    }
+}
+
+// Reads the triangles of an ASCII or binary STL file. A binary file is recognised by its
+// length matching the triangle count in its header, as some exporters also start binary
+// files with "solid"
+pub fn read_stl(filename: &str) -> Vec<[Vector3<f64>; 3]> {
+    let bytes = match fs::read(filename) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            println!("Error: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let mut triangles = if bytes.len() >= 84
+        && bytes.len()
+            == 84 + 50 * u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize
+    {
+        read_binary_stl(&bytes, (bytes.len() - 84) / 50)
+    } else if bytes.starts_with(b"solid") {
+        read_ascii_stl(&String::from_utf8_lossy(&bytes))
+    } else {
+        println!("{} is not an STL file", filename);
+        process::exit(1);
+    };
+
+    // Zero area facets have no closest point and touch nothing the neighbouring facets miss
+    triangles.retain(|[a, b, c]| (b - a).cross(&(c - a)).norm() > 0.0);
+    triangles
+}
+
+// Each record is a normal and three vertices as little endian f32 and a u16 attribute
+fn read_binary_stl(bytes: &[u8], count: usize) -> Vec<[Vector3<f64>; 3]> {
+    let float = |offset: usize| {
+        f32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ]) as f64
+    };
+    let vertex = |offset: usize| Vector3::new(float(offset), float(offset + 4), float(offset + 8));
+
+    (0..count)
+        .map(|triangle| {
+            let record = 84 + 50 * triangle;
+            [
+                vertex(record + 12),
+                vertex(record + 24),
+                vertex(record + 36),
+            ]
+        })
+        .collect()
+}
+
+// Only the vertex lines are used, the normals are recomputed when needed
+fn read_ascii_stl(text: &str) -> Vec<[Vector3<f64>; 3]> {
+    let mut vertices = Vec::new();
+    for line in text.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.first() == Some(&"vertex") {
+            if words.len() < 4 {
+                println!("STL vertex line is missing coordinates: {}", line);
+                process::exit(1);
+            }
+            vertices.push(Vector3::new(
+                words[1].parse::<f64>().unwrap(),
+                words[2].parse::<f64>().unwrap(),
+                words[3].parse::<f64>().unwrap(),
+            ));
+        }
+    }
+
+    if !vertices.len().is_multiple_of(3) {
+        println!("STL file has a facet without three vertices");
+        process::exit(1);
+    }
+
+    vertices
+        .chunks(3)
+        .map(|vertex| [vertex[0], vertex[1], vertex[2]])
+        .collect()
+}
+
+// Point of the triangle abc closest to p, on its face, an edge or a vertex (Ericson, Real-Time
+// Collision Detection 5.1.5)
+pub fn closest_point_on_triangle(
+    p: Vector3<f64>,
+    a: Vector3<f64>,
+    b: Vector3<f64>,
+    c: Vector3<f64>,
+) -> Vector3<f64> {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+
+    let d1 = ab.dot(&ap);
+    let d2 = ac.dot(&ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+
+    let bp = p - b;
+    let d3 = ab.dot(&bp);
+    let d4 = ac.dot(&bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + d1 / (d1 - d3) * ab;
+    }
+
+    let cp = p - c;
+    let d5 = ab.dot(&cp);
+    let d6 = ac.dot(&cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + d2 / (d2 - d6) * ac;
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return b + (d4 - d3) / ((d4 - d3) + (d5 - d6)) * (c - b);
+    }
+
+    // Inside the face
+    let denominator = 1.0 / (va + vb + vc);
+    a + ab * (vb * denominator) + ac * (vc * denominator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(point: Vector3<f64>, expected: Vector3<f64>) {
+        assert!(
+            (point - expected).norm() < 1e-12,
+            "{:?} is not {:?}",
+            point,
+            expected
+        );
+    }
+
+    #[test]
+    fn closest_point_in_each_region() {
+        let a = Vector3::new(0.0, 0.0, 0.0);
+        let b = Vector3::new(1.0, 0.0, 0.0);
+        let c = Vector3::new(0.0, 1.0, 0.0);
+        let closest =
+            |x: f64, y: f64, z: f64| closest_point_on_triangle(Vector3::new(x, y, z), a, b, c);
+
+        // Vertices
+        assert_close(closest(-1.0, -1.0, 1.0), a);
+        assert_close(closest(2.0, -0.5, 0.3), b);
+        assert_close(closest(-0.2, 2.0, 0.0), c);
+        // Edges
+        assert_close(closest(0.5, -1.0, 2.0), Vector3::new(0.5, 0.0, 0.0));
+        assert_close(closest(-1.0, 0.5, -1.0), Vector3::new(0.0, 0.5, 0.0));
+        assert_close(closest(1.0, 1.0, 3.0), Vector3::new(0.5, 0.5, 0.0));
+        // Face, from either side
+        assert_close(closest(0.2, 0.3, 5.0), Vector3::new(0.2, 0.3, 0.0));
+        assert_close(closest(0.2, 0.3, -5.0), Vector3::new(0.2, 0.3, 0.0));
+    }
+
+    fn write_temporary(name: &str, bytes: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    const TRIANGLES: [[[f32; 3]; 3]; 2] = [
+        [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+        [[0.5, 0.5, 2.0], [1.5, 0.5, 2.0], [0.5, -1.0, 2.0]],
+    ];
+
+    fn assert_triangles(triangles: &[[Vector3<f64>; 3]]) {
+        assert_eq!(triangles.len(), TRIANGLES.len());
+        for (triangle, expected) in triangles.iter().zip(TRIANGLES) {
+            for (vertex, expected) in triangle.iter().zip(expected) {
+                assert_eq!(*vertex, Vector3::from(expected.map(|x| x as f64)));
+            }
+        }
+    }
+
+    #[test]
+    fn reads_ascii_stl() {
+        let mut text = String::from("solid test\n");
+        // A zero area facet is dropped
+        for triangle in TRIANGLES.iter().chain([&[[2.0, 2.0, 2.0]; 3]]) {
+            text += "  facet normal 0 0 1\n    outer loop\n";
+            for [x, y, z] in triangle {
+                text += &format!("      vertex {} {} {}\n", x, y, z);
+            }
+            text += "    endloop\n  endfacet\n";
+        }
+        text += "endsolid test\n";
+
+        let path = write_temporary("ascii.stl", text.as_bytes());
+        assert_triangles(&read_stl(&path));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads_binary_stl_with_solid_header() {
+        // Some exporters start the binary header with "solid" too
+        let mut bytes = b"solid binary".to_vec();
+        bytes.resize(80, 0);
+        bytes.extend((TRIANGLES.len() as u32).to_le_bytes());
+        for triangle in TRIANGLES {
+            bytes.extend([0.0f32, 0.0, 1.0].iter().flat_map(|x| x.to_le_bytes()));
+            for vertex in triangle {
+                bytes.extend(vertex.iter().flat_map(|x| x.to_le_bytes()));
+            }
+            bytes.extend([0u8; 2]);
+        }
+
+        let path = write_temporary("binary.stl", &bytes);
+        assert_triangles(&read_stl(&path));
+        fs::remove_file(path).unwrap();
+    }
+}
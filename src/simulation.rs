@@ -1,9 +1,14 @@
+mod bond;
 mod calculations;
+pub(crate) mod contact;
 pub(crate) mod grid;
+mod insert;
+mod outlet;
 mod print;
 use core::f64::consts::PI;
+use std::process;
 
-use nalgebra::{Matrix3, Vector3};
+use nalgebra::{Matrix3, Vector2, Vector3};
 
 use crate::domain;
 use crate::sphere;
@@ -13,13 +18,67 @@ pub fn handle_commands(
     mut d_data: domain::DomainData,
     mut p_data: sphere::ParticleData,
 ) {
-    generate_material_map(&mut p_data);
+    generate_interaction_table(&mut p_data);
+
+    if p_data.cohesion_model == sphere::CohesionModel::Jkr
+        && p_data.contact_law != sphere::ContactLaw::Hertz
+        && p_data.contact_law != sphere::ContactLaw::HertzMindlin
+    {
+        println!("JKR cohesion replaces the elastic law and needs a Hertz contact law");
+        process::exit(1);
+    }
+    // Images are shifted along the flow axis when wrapping on the gradient axis
+    let velocity_gradient = d_data.velocity_gradient();
+    for flow in 0..3 {
+        for gradient in 0..3 {
+            if flow != gradient
+                && velocity_gradient[(flow, gradient)] != 0.0
+                && d_data.periodic[gradient]
+                && !d_data.periodic[flow]
+            {
+                println!("Shear needs the flow axis to be periodic, use LEB 0.0 with walls on it");
+                process::exit(1);
+            }
+        }
+        if velocity_gradient[(flow, flow)] != 0.0 && !d_data.periodic[flow] {
+            println!("VELOCITY_GRADIENT can only stretch periodic axes, move walls instead");
+            process::exit(1);
+        }
+    }
+    d_data.cell_order = match d_data.shear_order() {
+        Some(order) => order,
+        None => {
+            println!("The shear components go round the axes in a loop, which rotates the cell");
+            process::exit(1);
+        }
+    };
+    if d_data.barostat.is_some() && !d_data.periodic[d_data.gradient_axis] {
+        println!(
+            "BAROSTAT needs the gradient axis to be periodic, use a SERVO wall with walls on it"
+        );
+        process::exit(1);
+    }
+    if d_data.barostat.is_some()
+        && velocity_gradient[(d_data.gradient_axis, d_data.gradient_axis)] != 0.0
+    {
+        println!("BAROSTAT sets the strain rate of the gradient axis, VELOCITY_GRADIENT cannot");
+        process::exit(1);
+    }
+    set_interaction_range(&mut p_data);
 
     for command in command_stack {
         let results: Vec<&str> = command.split_whitespace().collect();
 
         match &results[0][0..3] {
+            "PAI" => {
+                set_pair_interaction(&mut p_data, &results);
+                set_interaction_range(&mut p_data);
+            }
             "REL" => relax(&mut d_data, &mut p_data),
+            "BON" => bond::bond_region(&mut d_data, &mut p_data, &results),
+            "SHE" => set_shear_schedule(&mut d_data, &p_data, &results),
+            "INS" => insert::add_inserter(&mut d_data, &mut p_data, &results),
+            "OUT" => outlet::add_outlet(&mut d_data, &results),
             "CYC" => {
                 //If updateRate and clear rate are not set, the default is used
                 let mut update_rate = 2500;
@@ -44,31 +103,192 @@ pub fn handle_commands(
                     results[1].parse::<i32>().unwrap()
                 );
             }
-            _ => {}
+            _ => {
+                println!("Unknown command {}", results[0]);
+                process::exit(1);
+            }
         }
     }
 }
 
-fn generate_material_map(p_data: &mut sphere::ParticleData) {
+pub fn restitution_to_beta(restitution_coefficient: f64) -> f64 {
+    let log_e = restitution_coefficient.ln();
+    -log_e / (PI * PI + log_e * log_e).sqrt()
+}
+
+// Fills the dense pair table from mixing rules, restitution and sliding friction default to
+// the global DAMPING and FRICTION values
+fn generate_interaction_table(p_data: &mut sphere::ParticleData) {
+    p_data.interactions.clear();
+
     for material1 in &p_data.materials {
         for material2 in &p_data.materials {
-            let s = format!("{} {}", material1.id, material2.id);
-
-            let effective = sphere::EffectiveMaterialPreCalc {
-                eff_radius: 1.0 / (1.0 / material1.radius + 1.0 / material2.radius),
+            let shear_mod1 = material1.youngs_mod / (2.0 * (1.0 + material1.poisson_ratio));
+            let shear_mod2 = material2.youngs_mod / (2.0 * (1.0 + material2.poisson_ratio));
 
+            let interaction = sphere::InteractionPair {
                 eff_youngs_mod: 1.0
                     / ((1.0 - material1.poisson_ratio * material1.poisson_ratio)
                         / material1.youngs_mod
                         + (1.0 - material2.poisson_ratio * material2.poisson_ratio)
                             / material2.youngs_mod),
+                eff_shear_mod: 1.0
+                    / ((2.0 - material1.poisson_ratio) / shear_mod1
+                        + (2.0 - material2.poisson_ratio) / shear_mod2),
+                beta: p_data.beta,
+                friction: p_data.friction,
+                rolling_friction: 0.5 * (material1.rolling_friction + material2.rolling_friction),
+                twisting_friction: 0.5
+                    * (material1.twisting_friction + material2.twisting_friction),
+                work_of_adhesion: 2.0
+                    * (material1.surface_energy * material2.surface_energy).sqrt(),
+                // The softer material yields first, an elastic material never yields
+                yield_pressure: if material1.yield_pressure == 0.0 {
+                    material2.yield_pressure
+                } else if material2.yield_pressure == 0.0 {
+                    material1.yield_pressure
+                } else {
+                    material1.yield_pressure.min(material2.yield_pressure)
+                },
             };
 
-            p_data.sphere_material_map.insert(s, effective);
+            p_data.interactions.push(interaction);
+        }
+    }
+}
+
+// PAIR id1 id2 followed by name value pairs, overriding the mixing rules for that pair
+fn set_pair_interaction(p_data: &mut sphere::ParticleData, results: &[&str]) {
+    let material_index = |id: &str| {
+        let id = id.parse::<i32>().unwrap();
+        match p_data.materials.iter().position(|m| m.id == id) {
+            Some(index) => index,
+            None => {
+                println!("PAIR uses unknown material {}", id);
+                process::exit(1);
+            }
+        }
+    };
+    let index1 = material_index(results[1]);
+    let index2 = material_index(results[2]);
+    let n_materials = p_data.materials.len();
+
+    for (a, b) in [(index1, index2), (index2, index1)] {
+        let interaction = &mut p_data.interactions[a * n_materials + b];
+
+        for property in results[3..].chunks(2) {
+            if property.len() < 2 {
+                println!("Pair property {} is missing a value", property[0]);
+                process::exit(1);
+            }
+            let value = property[1].parse::<f64>().unwrap();
+            match property[0] {
+                "RESTITUTION" => {
+                    if value <= 0.0 || value > 1.0 {
+                        println!("Pair restitution must be above 0 and at most 1");
+                        process::exit(1);
+                    }
+                    interaction.beta = restitution_to_beta(value);
+                }
+                "FRICTION" => interaction.friction = value,
+                "ROLLING" => interaction.rolling_friction = value,
+                "TWISTING" => interaction.twisting_friction = value,
+                "COHESION" => interaction.work_of_adhesion = value,
+                "YIELD" => interaction.yield_pressure = value,
+                _ => {
+                    println!("Unknown pair property {}", property[0]);
+                    process::exit(1);
+                }
+            }
         }
     }
 }
 
+// SHEAR followed by a schedule name and its values, starting now. Replaces the LEB rate until
+// the next SHEAR
+fn set_shear_schedule(
+    d_data: &mut domain::DomainData,
+    p_data: &sphere::ParticleData,
+    results: &[&str],
+) {
+    if d_data.periodic[d_data.gradient_axis] && !d_data.periodic[d_data.flow_axis] {
+        println!("Lees-Edwards shear needs the flow axis to be periodic, SHEAR cannot be used with walls on it");
+        process::exit(1);
+    }
+
+    let values: Vec<f64> = results[2..]
+        .iter()
+        .map(|value| value.parse::<f64>().unwrap())
+        .collect();
+    d_data.shear_schedule = match (results[1], values.len()) {
+        ("CONSTANT", 1) => {
+            d_data.lees_edwards_boundary = values[0];
+            domain::ShearSchedule::Constant
+        }
+        ("OSCILLATE", 2) => domain::ShearSchedule::Oscillating {
+            amplitude: values[0],
+            frequency: values[1],
+        },
+        ("RAMP", 3) => domain::ShearSchedule::Ramp {
+            from: values[0],
+            to: values[1],
+            duration: values[2],
+        },
+        ("STEP", 3) => domain::ShearSchedule::Step {
+            before: values[0],
+            after: values[1],
+            time: values[2],
+        },
+        ("REVERSE", 2) => domain::ShearSchedule::Reversal {
+            rate: values[0],
+            period: values[1],
+        },
+        _ => {
+            println!(
+                "Unknown SHEAR schedule or wrong number of values {}",
+                results[1..].join(" ")
+            );
+            process::exit(1);
+        }
+    };
+    d_data.shear_start = p_data.time;
+}
+
+// Largest gap beyond touching at which any pair of materials can still interact, the
+// neighbor search is widened by this much
+fn set_interaction_range(p_data: &mut sphere::ParticleData) {
+    let mut interaction_range: f64 = 0.0;
+
+    if p_data.cohesion_model == sphere::CohesionModel::Jkr {
+        let n_materials = p_data.materials.len();
+        for (index1, material1) in p_data.materials.iter().enumerate() {
+            for (index2, material2) in p_data.materials.iter().enumerate() {
+                let interaction = &p_data.interactions[index1 * n_materials + index2];
+
+                interaction_range = interaction_range.max(contact::jkr_separation_distance(
+                    1.0 / (1.0 / material1.radius + 1.0 / material2.radius),
+                    interaction.eff_youngs_mod,
+                    interaction.work_of_adhesion,
+                ));
+            }
+        }
+    }
+
+    if p_data.surface_tension > 0.0 {
+        for material1 in &p_data.materials {
+            for material2 in &p_data.materials {
+                let volume = 0.5 * (material1.liquid_volume + material2.liquid_volume);
+                let contact_angle = 0.5 * (material1.contact_angle + material2.contact_angle);
+
+                interaction_range =
+                    interaction_range.max(contact::rupture_distance(volume, contact_angle));
+            }
+        }
+    }
+
+    p_data.interaction_range = interaction_range;
+}
+
 fn relax(d_data: &mut domain::DomainData, p_data: &mut sphere::ParticleData) {
     // print_vtp(p_data, -1);
     let mut still_relaxing = true;
@@ -78,7 +298,7 @@ fn relax(d_data: &mut domain::DomainData, p_data: &mut sphere::ParticleData) {
             // println!("{:?}", p_data.position[i]);
             p_data.position[i] += p_data.velocity[i] * 0.00013;
             // println!("{:?}", p_data.velocity[i]);
-            p_data.velocity[i] = p_data.velocity[i] * 0.5;
+            p_data.velocity[i] *= 0.5;
             p_data.is_collision[i] = false;
         }
         grid::update(d_data, p_data);
@@ -94,16 +314,15 @@ fn relax(d_data: &mut domain::DomainData, p_data: &mut sphere::ParticleData) {
     }
 
     //Set lees Edwards boundary condition velocity here, Also calculate volume fraction
+    let mut shear = d_data.velocity_gradient();
+    shear.fill_diagonal(0.0);
     let mut volume = 0.0;
     for i in 0..p_data.radius.len() {
         volume += 4.0 / 3.0 * PI * p_data.radius[i].powi(3);
 
-        let x_velocity: f64 =
-            (p_data.position[i][1] - d_data.domain[1] * 0.5) * d_data.lees_edwards_boundary;
-
         // let y: f64 = rng.gen();
         // let z: f64 = rng.gen();
-        p_data.velocity[i] = Vector3::new(x_velocity, 0.0, 0.0);
+        p_data.velocity[i] = shear * (p_data.position[i] - d_data.domain * 0.5);
 
         //p_data.velocity[i] = Eigen::Vector3d((randf()-0.5)*d_data.lees_edwards_boundary*d_data.domain(0),(randf()-0.5)*d_data.lees_edwards_boundary*d_data.domain(0),(randf()-0.5)*d_data.lees_edwards_boundary*d_data.domain(0));
     }
@@ -123,7 +342,9 @@ fn cycle(
     update_rate: i32,
     clear_rate: i32,
 ) {
-    let dt = calculate_delta_time(p_data);
+    let dt = calculate_delta_time(d_data, p_data);
+
+    let model = contact::contact_model(&p_data.contact_law);
 
     let mut f_data = sphere::ForceData {
         particle_indexes: Vec::new(),
@@ -136,77 +357,192 @@ fn cycle(
     let mut collision_tensor = Matrix3::zeros();
     let mut average_reset_count = 0;
 
-    let mut ledisplace = 0.0;
+    // Sums of the shear stress against the first and third harmonics of the imposed strain over
+    // the current oscillation period, for the moduli
+    let mut response_period = -1;
+    let mut response_time = 0.0;
+    let mut harmonics = [Vector2::zeros(); 2];
 
     for cycle_count in 0..total_cycles {
         //Update velocity and position based on forces
         // grid::euler_integration(p_data, dt);
 
+        // Taken at the middle of the step, like the wall motions
+        d_data.lees_edwards_boundary = d_data.shear_rate(p_data.time + 0.5 * dt);
+
         grid::inital_integrate(p_data, dt);
+        grid::move_walls(d_data, p_data.time, dt);
+        for mesh in &mut d_data.meshes {
+            mesh.move_mesh(dt);
+        }
+        p_data.time += dt;
+        insert::insert_particles(d_data, p_data, dt);
+        outlet::remove_outlet_particles(d_data, p_data);
 
         //Boundary Conditions
-        grid::lees_edwards_boundaries(d_data, p_data, dt, ledisplace);
+        grid::periodic_boundaries(d_data, p_data);
 
-        //Resets if a particle is in collision, and resets forces to zero
+        //Resets if a particle is in collision, and resets forces and torques to zero
         for i in 0..p_data.radius.len() {
             p_data.is_collision[i] = false;
             p_data.force[i] = Vector3::new(0.0, 0.0, 0.0);
+            p_data.torque[i] = Vector3::new(0.0, 0.0, 0.0);
         }
 
         f_data.forcedata.clear();
 
-        grid::update(d_data, p_data);
-        grid::collisions(d_data, p_data, &mut f_data, dt, ledisplace);
+        if d_data.sort_interval > 0 && cycle_count % d_data.sort_interval == 0 {
+            grid::sort_particles(d_data, p_data);
+        }
+        grid::update_neighbors(d_data, p_data);
+        grid::collisions(d_data, p_data, &mut f_data, model.as_ref(), dt);
+        grid::wall_collisions(d_data, p_data, &mut f_data, model.as_ref(), dt);
+        grid::mesh_collisions(d_data, p_data, &mut f_data, model.as_ref(), dt);
+        bond::bond_forces(d_data, p_data, &mut f_data, dt);
 
         grid::final_integrate(p_data, dt);
         //Brute Force Collision Detection, this Updates the forces on each particle
-        // grid::_simp_collisions(d_data, p_data, &mut f_data, dt, ledisplace);
+        // grid::_simp_collisions(d_data, p_data, &mut f_data, model.as_ref(), dt);
 
         //calculates the kinetic stress tensor
+        let kinetic = calculations::kinetic_tensor(p_data, d_data);
+        let collision = calculations::collision_tensor(&f_data, d_data);
         kinetic_tensor =
-            calculations::calc_kinetic_tensor(p_data, d_data, kinetic_tensor, average_reset_count);
-        collision_tensor = calculations::calc_collision_tensor(
-            &f_data,
-            d_data,
-            collision_tensor,
-            average_reset_count,
-        );
+            calculations::running_average(kinetic_tensor, kinetic, average_reset_count);
+        collision_tensor =
+            calculations::running_average(collision_tensor, collision, average_reset_count);
         average_reset_count += 1;
 
+        // Projects the shear stress onto the strain, reporting each whole period
+        if let domain::ShearSchedule::Oscillating {
+            amplitude,
+            frequency,
+        } = d_data.shear_schedule
+        {
+            let elapsed = p_data.time - d_data.shear_start;
+            let period = (elapsed * frequency).floor() as i64;
+            if period != response_period {
+                if response_time > 1.0 / frequency - 1.5 * dt {
+                    let moduli =
+                        calculations::oscillatory_moduli(&harmonics, amplitude, 1.0 / frequency);
+                    print::print_moduli(moduli, response_period, cycle_count);
+                }
+                response_period = period;
+                response_time = 0.0;
+                harmonics = [Vector2::zeros(); 2];
+            }
+
+            // The stress tensor is positive in compression, the shear stress opposing positive
+            // shear is its negative flow gradient component
+            let shear_stress = -(kinetic + collision)[(d_data.flow_axis, d_data.gradient_axis)];
+            let phase = 2.0 * PI * frequency * elapsed;
+            harmonics[0] += shear_stress * dt * Vector2::new(phase.sin(), phase.cos());
+            harmonics[1] +=
+                shear_stress * dt * Vector2::new((3.0 * phase).sin(), (3.0 * phase).cos());
+            response_time += dt;
+        }
+
         //Print statments to terminal and prints the VTP, and Stress data to fikkk,k,mles
         if cycle_count % update_rate == 0 {
             // println!("CYC {} completed", cycle_count);
             // println!("{}", p_data.velocity[0][0]);
             println!("{:?}", (kinetic_tensor + collision_tensor));
+            if d_data.barostat.is_some() || d_data.imposed_gradient.diagonal() != Vector3::zeros() {
+                println!(
+                    "Cell {} {} {} volume fraction {}",
+                    d_data.domain[0], d_data.domain[1], d_data.domain[2], p_data.volume_fraction
+                );
+            }
+            if p_data.bonds.len() + p_data.broken_bonds > 0 {
+                println!(
+                    "Bonds intact {} broken {}",
+                    p_data.bonds.len(),
+                    p_data.broken_bonds
+                );
+            }
 
             print::print_vtp(p_data, cycle_count);
         }
 
         //Resets the averaging of the kinetic tensor
         if cycle_count % clear_rate == 0 {
-            print::print_stress(kinetic_tensor, collision_tensor, cycle_count);
+            print::print_stress(d_data, kinetic_tensor, collision_tensor, cycle_count);
+            if p_data.bonds.len() + p_data.broken_bonds > 0 {
+                print::print_bonds(p_data, cycle_count);
+            }
+            if !d_data.walls.is_empty() {
+                print::print_walls(d_data, average_reset_count, cycle_count);
+                for wall in &mut d_data.walls {
+                    wall.force_sum = Vector3::zeros();
+                }
+            }
+            if !d_data.outlets.is_empty() {
+                print::print_outlets(d_data, average_reset_count as f64 * dt, cycle_count);
+                for outlet in &mut d_data.outlets {
+                    outlet.count = 0;
+                    outlet.mass = 0.0;
+                }
+            }
+            if !d_data.meshes.is_empty() {
+                print::print_meshes(d_data, average_reset_count, cycle_count);
+                for mesh in &mut d_data.meshes {
+                    mesh.force = Vector3::zeros();
+                    mesh.torque = Vector3::zeros();
+                }
+            }
 
             kinetic_tensor = Matrix3::zeros();
             collision_tensor = Matrix3::zeros();
             average_reset_count = 0;
         }
 
-        ledisplace += dt * d_data.lees_edwards_boundary * d_data.domain[1];
-        ledisplace -= (ledisplace / d_data.domain[0]).floor() * d_data.domain[0];
+        // Deforms the cell with the velocity gradient, the barostat straining the gradient axis
+        // towards the target stress with this steps stress
+        let mut velocity_gradient = d_data.velocity_gradient();
+        if let Some(domain::Barostat { stress, gain }) = d_data.barostat {
+            let gradient = d_data.gradient_axis;
+            velocity_gradient[(gradient, gradient)] =
+                gain * ((kinetic + collision)[(gradient, gradient)] - stress);
+        }
+        grid::deform_cell(d_data, p_data, velocity_gradient, dt);
     }
 }
 
-fn calculate_delta_time(p_data: &sphere::ParticleData) -> f64 {
+fn calculate_delta_time(d_data: &domain::DomainData, p_data: &sphere::ParticleData) -> f64 {
     //Checks each particles Size for the smallest delta time the simulation should use
     let mut dt: f64 = 0.001;
     for i in 0..p_data.radius.len() {
-        let g = p_data.youngs_mod[i] / (2.0 * (1.0 + p_data.poisson_ratio[i]));
-        let alpha = 0.1631 * p_data.poisson_ratio[i] + 0.876605;
-        let delta = PI * p_data.radius[i] / alpha * (p_data.density[i] / g).sqrt();
-        dt = delta.min(dt);
+        dt = dt.min(rayleigh_time(
+            p_data.radius[i],
+            p_data.youngs_mod[i],
+            p_data.poisson_ratio[i],
+            p_data.density[i],
+        ));
     }
+    // and the spheres still to be inserted, which can be smaller than any present
+    for inserter in &d_data.inserters {
+        for &(material, _) in &inserter.materials {
+            let material = &p_data.materials[material];
+            let radius = inserter
+                .radius_range
+                .map_or(material.radius, |(min_radius, _)| min_radius);
+            dt = dt.min(rayleigh_time(
+                radius,
+                material.youngs_mod,
+                material.poisson_ratio,
+                material.density,
+            ));
+        }
+    }
+    dt = dt.min(bond::critical_time_step(p_data));
 
     println!("Useing {} for delta time", dt * 0.5);
     //Fractional Factor set to 0.5 here,
-    return dt * 0.5;
+    dt * 0.5
+}
+
+fn rayleigh_time(radius: f64, youngs_mod: f64, poisson_ratio: f64, density: f64) -> f64 {
+    let g = youngs_mod / (2.0 * (1.0 + poisson_ratio));
+    let alpha = 0.1631 * poisson_ratio + 0.876605;
+    PI * radius / alpha * (density / g).sqrt()
 }
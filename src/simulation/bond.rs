@@ -0,0 +1,204 @@
+use core::f64::consts::PI;
+use std::process;
+
+use nalgebra::Vector3;
+
+use super::contact;
+use super::grid;
+use crate::domain;
+use crate::sphere;
+
+// BOND x_min y_min z_min x_max y_max z_max normal_stiffness shear_stiffness tensile_strength
+// shear_strength followed by optional name value pairs. Every pair of touching spheres with
+// both centres in the region is bonded, touching meaning a gap below GAP times the smaller
+// radius. The bond radius is RADIUS times the smaller sphere radius
+pub fn bond_region(
+    d_data: &mut domain::DomainData,
+    p_data: &mut sphere::ParticleData,
+    results: &[&str],
+) {
+    if results.len() < 11 {
+        println!("BOND needs a region, normal and shear stiffness, tensile and shear strength");
+        process::exit(1);
+    }
+    let value = |index: usize| results[index].parse::<f64>().unwrap();
+
+    let lower = Vector3::new(value(1), value(2), value(3));
+    let upper = Vector3::new(value(4), value(5), value(6));
+
+    let mut radius_multiplier = 1.0;
+    let mut gap = 0.05;
+    for property in results[11..].chunks(2) {
+        if property.len() < 2 {
+            println!("Bond property {} is missing a value", property[0]);
+            process::exit(1);
+        }
+        let value = property[1].parse::<f64>().unwrap();
+        match property[0] {
+            "RADIUS" => radius_multiplier = value,
+            "GAP" => gap = value,
+            _ => {
+                println!("Unknown bond property {}", property[0]);
+                process::exit(1);
+            }
+        }
+    }
+
+    let in_region = |position: Vector3<f64>| {
+        (0..3).all(|axis| position[axis] >= lower[axis] && position[axis] <= upper[axis])
+    };
+
+    // Candidate pairs from a neighbor list built with a skin reaching the largest gap, which is
+    // built again with the run skin at the next step
+    let skin = d_data.neighbors.skin;
+    d_data.neighbors.skin = skin.max(gap * p_data.max_radius);
+    d_data.neighbors.positions.clear();
+    grid::update_neighbors(d_data, p_data);
+    d_data.neighbors.skin = skin;
+    d_data.neighbors.positions.clear();
+
+    let mut count = 0;
+    for &(i, j) in &d_data.neighbors.pairs {
+        if !in_region(p_data.position[i]) || !in_region(p_data.position[j]) {
+            continue;
+        }
+
+        let pair = grid::periodic_pair(d_data, p_data, i, j);
+        let distance = pair.delta_position.norm();
+        let min_radius = p_data.radius[i].min(p_data.radius[j]);
+
+        if distance - (p_data.radius[i] + p_data.radius[j]) > gap * min_radius {
+            continue;
+        }
+
+        p_data.bonds.insert(
+            (i, j),
+            sphere::Bond {
+                radius: radius_multiplier * min_radius,
+                length: distance,
+                normal_stiffness: value(7),
+                shear_stiffness: value(8),
+                tensile_strength: value(9),
+                shear_strength: value(10),
+                shear_force: Vector3::zeros(),
+                bending_moment: Vector3::zeros(),
+                twisting_moment: Vector3::zeros(),
+            },
+        );
+        count += 1;
+    }
+
+    println!("Bonded {} pairs", count);
+}
+
+// Oscillation period scale sqrt(m / k) of the stiffest bond, in translation and rotation, which
+// can be far shorter than the Rayleigh time of the spheres
+pub fn critical_time_step(p_data: &sphere::ParticleData) -> f64 {
+    let mut dt = f64::INFINITY;
+    for (&(i, j), bond) in &p_data.bonds {
+        let area = PI * bond.radius * bond.radius;
+        let polar_moment = 0.5 * PI * bond.radius.powi(4);
+
+        let mass = p_data.mass[i].min(p_data.mass[j]);
+        let moment_of_inertia = p_data.moment_of_inertia[i].min(p_data.moment_of_inertia[j]);
+
+        let stiffness = bond.normal_stiffness.max(bond.shear_stiffness) * area;
+        let rotational_stiffness =
+            (0.5 * bond.normal_stiffness).max(bond.shear_stiffness) * polar_moment;
+
+        dt = dt
+            .min((mass / stiffness).sqrt())
+            .min((moment_of_inertia / rotational_stiffness).sqrt());
+    }
+    dt
+}
+
+// Adds the parallel bond forces and moments to both spheres. The normal force comes from the
+// stretch of the bond, the shear force and the moments are integrated from the relative motion.
+// A bond breaks once the peak tensile or shear stress on its rim exceeds its strength
+pub fn bond_forces(
+    d_data: &domain::DomainData,
+    p_data: &mut sphere::ParticleData,
+    f_data: &mut sphere::ForceData,
+    dt: f64,
+) {
+    let mut bonds = std::mem::take(&mut p_data.bonds);
+
+    // In index order, as the hash map order changes from run to run
+    let mut keys: Vec<(usize, usize)> = bonds.keys().copied().collect();
+    keys.sort_unstable();
+    for (i, j) in keys {
+        let bond = bonds.get_mut(&(i, j)).unwrap();
+        if !apply_bond(d_data, p_data, f_data, i, j, bond, dt) {
+            bonds.remove(&(i, j));
+            p_data.broken_bonds += 1;
+        }
+    }
+
+    p_data.bonds = bonds;
+}
+
+// Returns false, leaving the spheres untouched, when the bond breaks
+fn apply_bond(
+    d_data: &domain::DomainData,
+    p_data: &mut sphere::ParticleData,
+    f_data: &mut sphere::ForceData,
+    i: usize,
+    j: usize,
+    bond: &mut sphere::Bond,
+    dt: f64,
+) -> bool {
+    let pair = grid::periodic_pair(d_data, p_data, i, j);
+    let distance = pair.delta_position.norm();
+    let normalized_delta = pair.delta_position / distance;
+
+    let area = PI * bond.radius * bond.radius;
+    let polar_moment = 0.5 * PI * bond.radius.powi(4);
+    let area_moment = 0.5 * polar_moment;
+
+    // Relative velocity of j to i at the bond centre, as for a contact
+    let contact_velocity = pair.delta_velocity
+        - (p_data.radius[i] * p_data.angular_velocity[i]
+            + p_data.radius[j] * p_data.angular_velocity[j])
+            .cross(&normalized_delta);
+    let shear_velocity =
+        contact_velocity - normalized_delta.dot(&contact_velocity) * normalized_delta;
+
+    let relative_rotation = (p_data.angular_velocity[j] - p_data.angular_velocity[i]) * dt;
+    let twist = relative_rotation.dot(&normalized_delta) * normalized_delta;
+    let bend = relative_rotation - twist;
+
+    // Positive in compression
+    let normal_force = bond.normal_stiffness * area * (bond.length - distance);
+
+    bond.shear_force = contact::rotate_onto_plane(bond.shear_force, normalized_delta)
+        - bond.shear_stiffness * area * shear_velocity * dt;
+    bond.bending_moment = contact::rotate_onto_plane(bond.bending_moment, normalized_delta)
+        - bond.normal_stiffness * area_moment * bend;
+    bond.twisting_moment = bond.twisting_moment.dot(&normalized_delta) * normalized_delta
+        - bond.shear_stiffness * polar_moment * twist;
+
+    let tensile_stress =
+        -normal_force / area + bond.bending_moment.norm() * bond.radius / area_moment;
+    let shear_stress =
+        bond.shear_force.norm() / area + bond.twisting_moment.norm() * bond.radius / polar_moment;
+
+    if tensile_stress >= bond.tensile_strength || shear_stress >= bond.shear_strength {
+        return false;
+    }
+
+    let force = normal_force * normalized_delta + bond.shear_force;
+    let moment = bond.bending_moment + bond.twisting_moment;
+
+    p_data.force[i] -= force;
+    p_data.force[j] += force;
+
+    p_data.torque[i] -= moment + (p_data.radius[i] * normalized_delta).cross(&bond.shear_force);
+    p_data.torque[j] += moment - (p_data.radius[j] * normalized_delta).cross(&bond.shear_force);
+
+    f_data
+        .forcedata
+        .push(force * pair.delta_position.transpose());
+
+    true
+}
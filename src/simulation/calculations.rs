@@ -1,4 +1,5 @@
-use nalgebra::{Matrix3, Vector3};
+use nalgebra::{Matrix3, Vector2, Vector3};
+use rayon::prelude::*;
 
 use crate::{domain, sphere};
 
@@ -8,63 +9,163 @@ pub fn _get_kinetic_energy(p_data: &mut sphere::ParticleData) -> f64 {
     for i in 0..p_data.radius.len() {
         sum += 1.0 / 2.0 * p_data.mass[i] * p_data.velocity[i].dot(&p_data.velocity[i]);
     }
-    return sum;
+    sum
 }
 
-pub fn calc_kinetic_tensor(
-    p_data: &sphere::ParticleData,
-    d_data: &domain::DomainData,
-    kinetic_tensor: Matrix3<f64>,
-    average_reset_count: i32,
-) -> Matrix3<f64> {
+// Kinetic stress of the velocity fluctuations about the mean velocity this step
+pub fn kinetic_tensor(p_data: &sphere::ParticleData, d_data: &domain::DomainData) -> Matrix3<f64> {
     let n_particles = p_data.radius.len();
-    // Get average velocity
-    let mut average_velocity = Vector3::new(0.0, 0.0, 0.0);
-    for i in 0..p_data.radius.len() {
-        average_velocity += p_data.velocity[i];
+    // An empty cell, before any spheres are inserted
+    if n_particles == 0 {
+        return Matrix3::zeros();
     }
-    average_velocity = average_velocity.scale(1.0 / n_particles as f64);
-
-    // println!("{:?}", average_velocity);
-
-    // Get average kinetic tensor
-    let mut temp_kinetic_tensor = Matrix3::zeros();
+    // Get average velocity
+    let average_velocity = chunked_sum(
+        p_data
+            .velocity
+            .par_chunks(SUM_CHUNK)
+            .map(|velocities| velocities.iter().sum::<Vector3<f64>>()),
+    )
+    .scale(1.0 / n_particles as f64);
 
-    for i in 0..p_data.radius.len() {
-        temp_kinetic_tensor += p_data.mass[i]
-            * ((p_data.velocity[i] - average_velocity)
-                * (p_data.velocity[i] - average_velocity).transpose());
-    }
-    temp_kinetic_tensor = temp_kinetic_tensor.scale(1.0 / (d_data.domain_volume));
+    let temp_kinetic_tensor: Matrix3<f64> = chunked_sum(
+        p_data
+            .velocity
+            .par_chunks(SUM_CHUNK)
+            .zip(p_data.mass.par_chunks(SUM_CHUNK))
+            .map(|(velocities, masses)| {
+                velocities
+                    .iter()
+                    .zip(masses)
+                    .map(|(velocity, mass)| {
+                        let fluctuation = velocity - average_velocity;
+                        *mass * (fluctuation * fluctuation.transpose())
+                    })
+                    .sum()
+            }),
+    );
+    temp_kinetic_tensor.scale(1.0 / (d_data.domain_volume))
+}
 
-    // println!("{:?}", temp_kinetic_tensor);
+// Contact stress of this steps forces
+pub fn collision_tensor(f_data: &sphere::ForceData, d_data: &domain::DomainData) -> Matrix3<f64> {
+    let temp_collision_tensor: Matrix3<f64> = chunked_sum(
+        f_data
+            .forcedata
+            .par_chunks(SUM_CHUNK)
+            .map(|virials| virials.iter().sum()),
+    );
+    temp_collision_tensor.scale(1.0 / d_data.domain_volume)
+}
 
-    // average this frames kinetic tensor with prevous tensors
-    let kt: Matrix3<f64> = (kinetic_tensor.scale(average_reset_count as f64) + temp_kinetic_tensor)
-        .scale(1.0 / (average_reset_count + 1) as f64) as Matrix3<f64>;
-    // println!("{:?}", kt);
+// Parallel sums are taken over fixed chunks and then added in chunk order, so they round the
+// same way whatever the number of threads
+const SUM_CHUNK: usize = 1024;
 
-    return kt;
+fn chunked_sum<T: Send + std::iter::Sum>(chunks: impl IndexedParallelIterator<Item = T>) -> T {
+    chunks.collect::<Vec<T>>().into_iter().sum()
 }
 
-pub fn calc_collision_tensor(
-    f_data: &sphere::ForceData,
-    d_data: &domain::DomainData,
-    collision_tensor: Matrix3<f64>,
+// Folds this steps tensor into the average over the previous average_reset_count steps
+pub fn running_average(
+    average: Matrix3<f64>,
+    current: Matrix3<f64>,
     average_reset_count: i32,
 ) -> Matrix3<f64> {
-    let mut temp_collision_tensor = Matrix3::zeros();
+    (average.scale(average_reset_count as f64) + current)
+        .scale(1.0 / (average_reset_count + 1) as f64)
+}
+
+// Storage and loss moduli and the third harmonic ratio I3/I1 of one period of oscillatory
+// shear. Each harmonic holds the sums of shear stress * (sin, cos) of its phase times dt
+pub fn oscillatory_moduli(
+    harmonics: &[Vector2<f64>; 2],
+    amplitude: f64,
+    period: f64,
+) -> (f64, f64, f64) {
+    let scale = 2.0 / (amplitude * period);
+    (
+        harmonics[0][0] * scale,
+        harmonics[0][1] * scale,
+        harmonics[1].norm() / harmonics[0].norm(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for i in 0..f_data.forcedata.len() {
-        temp_collision_tensor += f_data.forcedata[i];
+    #[test]
+    fn stress_sums_are_the_same_on_any_number_of_threads() {
+        // Several chunks of values spanning orders of magnitude, so the rounding depends on
+        // the order they are added in
+        let count = 5 * SUM_CHUNK + 17;
+        let mut p_data = sphere::ParticleData::default();
+        let mut f_data = sphere::ForceData {
+            particle_indexes: Vec::new(),
+            force: Vec::new(),
+            del: Vec::new(),
+            forcedata: Vec::new(),
+        };
+        for index in 0..count {
+            let x = index as f64;
+            p_data.radius.push(1e-4);
+            p_data.mass.push(1e-9 * (1.0 + x.sin().abs()));
+            p_data.velocity.push(Vector3::new(
+                x.sin() * 10f64.powi((index % 7) as i32 - 3),
+                x.cos(),
+                (0.3 * x).sin() * 1e-3,
+            ));
+            f_data.forcedata.push(Matrix3::from_fn(|row, column| {
+                (x + (3 * row + column) as f64).cos() * 10f64.powi((index % 5) as i32 - 2)
+            }));
+        }
+        let d_data = domain::DomainData::default();
+
+        let sums = |threads: usize| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| {
+                    (
+                        kinetic_tensor(&p_data, &d_data),
+                        collision_tensor(&f_data, &d_data),
+                    )
+                })
+        };
+        let serial = sums(1);
+        for threads in [1, 2, 3, 8] {
+            assert_eq!(sums(threads), serial);
+        }
     }
-    temp_collision_tensor = temp_collision_tensor.scale(1.0 / d_data.domain_volume);
 
-    // average this frames kinetic tensor with prevous tensors
-    let ct: Matrix3<f64> = (collision_tensor.scale(average_reset_count as f64)
-        + temp_collision_tensor)
-        .scale(1.0 / (average_reset_count + 1) as f64) as Matrix3<f64>;
-    // println!("{:?}", kt);
+    #[test]
+    fn moduli_recover_a_viscoelastic_response() {
+        use core::f64::consts::PI;
+
+        // Stress in phase with the strain amplitude * sin(phase) through G', with its rate
+        // through G'', plus a third harmonic
+        let (amplitude, frequency) = (0.02, 50.0);
+        let (storage, loss, third) = (3.0e5, 1.0e5, 200.0);
+        let period = 1.0 / frequency;
+        let steps = 2000;
+        let dt = period / steps as f64;
 
-    return ct;
+        let mut harmonics = [Vector2::zeros(); 2];
+        for step in 0..steps {
+            let phase = 2.0 * PI * frequency * step as f64 * dt;
+            let shear_stress = amplitude * (storage * phase.sin() + loss * phase.cos())
+                + third * (3.0 * phase).sin();
+            harmonics[0] += shear_stress * dt * Vector2::new(phase.sin(), phase.cos());
+            harmonics[1] +=
+                shear_stress * dt * Vector2::new((3.0 * phase).sin(), (3.0 * phase).cos());
+        }
+
+        let (g_storage, g_loss, ratio) = oscillatory_moduli(&harmonics, amplitude, period);
+        assert!((g_storage - storage).abs() < 1e-6 * storage);
+        assert!((g_loss - loss).abs() < 1e-6 * storage);
+        let first = amplitude * (storage * storage + loss * loss).sqrt();
+        assert!((ratio - third / first).abs() < 1e-9);
+    }
 }
@@ -0,0 +1,942 @@
+use core::f64::consts::PI;
+
+use nalgebra::{Matrix3, Vector3};
+
+use super::restitution_to_beta;
+use crate::sphere;
+
+// A possibly colliding pair, with the position and velocity of j taken from the periodic
+// (or Lees-Edwards) image closest to i. For a wall j is the point of the wall nearest to i
+pub struct Pair {
+    pub i: usize,
+    pub j: Body,
+    pub delta_position: Vector3<f64>,
+    pub delta_velocity: Vector3<f64>,
+}
+
+// The second body of a pair
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Body {
+    Sphere(usize),
+    // A planar wall or mesh triangle of the given material index, a sphere of infinite radius
+    // and mass
+    Wall(usize),
+}
+
+impl Pair {
+    fn radius_j(&self, p_data: &sphere::ParticleData) -> f64 {
+        match self.j {
+            Body::Sphere(j) => p_data.radius[j],
+            Body::Wall(_) => f64::INFINITY,
+        }
+    }
+
+    fn mass_j(&self, p_data: &sphere::ParticleData) -> f64 {
+        match self.j {
+            Body::Sphere(j) => p_data.mass[j],
+            Body::Wall(_) => f64::INFINITY,
+        }
+    }
+
+    fn moment_of_inertia_j(&self, p_data: &sphere::ParticleData) -> f64 {
+        match self.j {
+            Body::Sphere(j) => p_data.moment_of_inertia[j],
+            Body::Wall(_) => f64::INFINITY,
+        }
+    }
+
+    fn angular_velocity_j(&self, p_data: &sphere::ParticleData) -> Vector3<f64> {
+        match self.j {
+            Body::Sphere(j) => p_data.angular_velocity[j],
+            Body::Wall(_) => Vector3::zeros(),
+        }
+    }
+
+    fn interaction<'a>(&self, p_data: &'a sphere::ParticleData) -> &'a sphere::InteractionPair {
+        match self.j {
+            Body::Sphere(j) => p_data.interaction(self.i, j),
+            Body::Wall(material) => p_data.material_interaction(self.i, material),
+        }
+    }
+
+    // Overlap of the solids, negative when apart
+    fn overlap(&self, p_data: &sphere::ParticleData) -> f64 {
+        let distance = self.delta_position.norm();
+        match self.j {
+            Body::Sphere(j) => p_data.radius[self.i] + p_data.radius[j] - distance,
+            Body::Wall(_) => p_data.radius[self.i] - distance,
+        }
+    }
+
+    // Distances from the centres to the contact point, which sits half the overlap inside each
+    // sphere or on the surface of a wall. The lever of a wall is zero as walls do not rotate
+    fn contact_levers(&self, p_data: &sphere::ParticleData, distance_delta: f64) -> (f64, f64) {
+        match self.j {
+            Body::Sphere(j) => (
+                p_data.radius[self.i] - 0.5 * distance_delta,
+                p_data.radius[j] - 0.5 * distance_delta,
+            ),
+            Body::Wall(_) => (p_data.radius[self.i] - distance_delta, 0.0),
+        }
+    }
+}
+
+// What a contact model hands back for one pair. The force acts on j and the opposite force
+// on i, the virial is the force times branch vector used for the collision stress tensor
+pub struct ContactForces {
+    pub force: Vector3<f64>,
+    pub torque_i: Vector3<f64>,
+    pub torque_j: Vector3<f64>,
+    pub virial: Matrix3<f64>,
+    pub touching: bool,
+}
+
+// Conservative part of the normal contact law at an overlap
+pub struct NormalContact {
+    pub force: f64,
+    pub stiffness: f64,
+    pub contact_radius: f64,
+}
+
+// A contact law between two spheres. Models give their normal law, tangential
+// stiffness and damping, and the shared evaluate adds cohesion, capillary bridges, friction
+// and rolling resistance on top. A model may also replace evaluate completely.
+pub trait ContactModel: Sync {
+    // History is passed for laws depending on the loading path and follows the same rule as
+    // in evaluate
+    fn normal_contact(
+        &self,
+        p_data: &sphere::ParticleData,
+        pair: &Pair,
+        history: &mut sphere::ContactHistory,
+        distance_delta: f64,
+    ) -> NormalContact;
+
+    // Zero gives a frictionless model
+    fn tangential_stiffness(
+        &self,
+        p_data: &sphere::ParticleData,
+        pair: &Pair,
+        contact_radius: f64,
+    ) -> f64;
+
+    // Viscous damping coefficient giving the restitution of beta for a spring of the given
+    // stiffness, the Hertz form (Tsuji et al. 1992) by default
+    fn damping(&self, beta: f64, stiffness: f64, reduced_mass: f64) -> f64 {
+        2.0 * 0.91287092917 * beta * (stiffness * reduced_mass).sqrt()
+    }
+
+    // Returns None when the pair does not interact. History is seen from i. Each pair is
+    // evaluated once a step, from the half neighbor list or the wall and mesh loops, and arrives
+    // with history.updated false. The caller sets it when storing the history back, and
+    // histories not updated during a step are dropped as separated
+    fn evaluate(
+        &self,
+        p_data: &sphere::ParticleData,
+        pair: &Pair,
+        history: &mut sphere::ContactHistory,
+        dt: f64,
+    ) -> Option<ContactForces> {
+        let i = pair.i;
+        let delta_position = pair.delta_position;
+
+        let distance = delta_position.norm();
+
+        let distance_delta = pair.overlap(p_data);
+
+        let interaction = pair.interaction(p_data);
+
+        let effective_radius = effective_radius(p_data, pair);
+        let effective_youngs = interaction.eff_youngs_mod;
+
+        let work_of_adhesion = interaction.work_of_adhesion;
+
+        if distance_delta <= 0.0 {
+            // A JKR neck formed on contact only breaks once the spheres are pulled past the
+            // separation distance
+            let is_jkr_neck = p_data.cohesion_model == sphere::CohesionModel::Jkr
+                && history.cohesive_contact
+                && -distance_delta
+                    < jkr_separation_distance(effective_radius, effective_youngs, work_of_adhesion);
+
+            // Likewise a liquid bridge formed on contact holds until its rupture distance
+            let is_liquid_bridge = p_data.surface_tension > 0.0
+                && history.liquid_bridge
+                && -distance_delta
+                    < rupture_distance(
+                        bridge_volume(p_data, pair),
+                        bridge_contact_angle(p_data, pair),
+                    );
+
+            if !is_jkr_neck {
+                if is_liquid_bridge {
+                    // The solids are apart, so only the bridge itself is remembered
+                    *history = sphere::ContactHistory {
+                        liquid_bridge: true,
+                        ..Default::default()
+                    };
+                    return Some(liquid_bridge_forces(
+                        p_data,
+                        pair,
+                        -distance_delta,
+                        effective_radius,
+                    ));
+                }
+                return None;
+            }
+        }
+
+        let normalized_delta = delta_position / distance;
+
+        let mut normal = self.normal_contact(p_data, pair, history, distance_delta);
+
+        let adhesive_force = match p_data.cohesion_model {
+            sphere::CohesionModel::None => 0.0,
+            sphere::CohesionModel::Dmt => 2.0 * PI * work_of_adhesion * effective_radius,
+            // Simplified JKR, a cohesion energy density of w / R acting on the contact area
+            // pi R delta gives an attraction linear in the overlap
+            sphere::CohesionModel::Linear => PI * work_of_adhesion * distance_delta,
+            // JKR replaces the elastic law, the adhesive neck changes the contact radius
+            sphere::CohesionModel::Jkr => {
+                let contact_radius = jkr_contact_radius(
+                    distance_delta,
+                    effective_radius,
+                    effective_youngs,
+                    work_of_adhesion,
+                );
+                normal = NormalContact {
+                    force: 4.0 / 3.0 * effective_youngs * contact_radius.powi(3) / effective_radius,
+                    stiffness: 2.0 * effective_youngs * contact_radius,
+                    contact_radius,
+                };
+                (8.0 * PI * work_of_adhesion * effective_youngs * contact_radius.powi(3)).sqrt()
+            }
+        };
+
+        // Inside a liquid bridge the capillary pull is at its maximum while the solids touch
+        let adhesive_force = if p_data.surface_tension > 0.0 {
+            adhesive_force
+                + capillary_force(p_data, pair, distance_delta.min(0.0), effective_radius)
+        } else {
+            adhesive_force
+        };
+
+        let reduced_mass = 1.0 / (1.0 / p_data.mass[i] + 1.0 / pair.mass_j(p_data));
+
+        let delta_veloctiy = pair.delta_velocity;
+        let f_dot = normalized_delta.dot(&delta_veloctiy);
+
+        // The approach speed when the solids first touch sets a tabulated restitution
+        if history.impact_velocity == 0.0 {
+            history.impact_velocity = (-f_dot).max(0.0);
+        }
+
+        let damping = |stiffness: f64| match &p_data.restitution_model {
+            sphere::RestitutionModel::Constant => {
+                self.damping(interaction.beta, stiffness, reduced_mass)
+            }
+            // Brilliantov et al. (1996) viscoelastic damping, F = A k d(delta)/dt, whose
+            // restitution falls with impact velocity (Schwager and Poschel 2008)
+            sphere::RestitutionModel::Viscoelastic {
+                dissipative_constant,
+            } => dissipative_constant * stiffness,
+            sphere::RestitutionModel::Tabulated {
+                velocities,
+                restitution,
+            } => self.damping(
+                restitution_to_beta(tabulated_restitution(
+                    velocities,
+                    restitution,
+                    history.impact_velocity,
+                )),
+                stiffness,
+                reduced_mass,
+            ),
+        };
+
+        let dissipation_force = damping(normal.stiffness) * f_dot;
+
+        let tangential_stiffness = self.tangential_stiffness(p_data, pair, normal.contact_radius);
+
+        // The sliding and rolling limits use the load without the adhesive pull, which is the
+        // usual offset of the Coulomb limit by the pull-off force for cohesive contacts
+        let contact = Contact {
+            normalized_delta,
+            contact_radius: normal.contact_radius,
+            effective_radius,
+            normal_stiffness: normal.stiffness,
+            normal_force: (normal.force - dissipation_force).max(0.0),
+            friction: interaction.friction,
+            tangential_stiffness,
+            tangential_damping: damping(tangential_stiffness),
+        };
+
+        // Relative velocity of j to i at the contact point, including the surface velocity
+        // from rotation
+        let (contact_radius_i, contact_radius_j) = pair.contact_levers(p_data, distance_delta);
+        let contact_velocity = delta_veloctiy
+            - (contact_radius_i * p_data.angular_velocity[i]
+                + contact_radius_j * pair.angular_velocity_j(p_data))
+            .cross(&normalized_delta);
+        let tangential_velocity =
+            contact_velocity - normalized_delta.dot(&contact_velocity) * normalized_delta;
+
+        let tangential_force = if tangential_stiffness > 0.0 {
+            tangential_force(&contact, history, tangential_velocity, dt)
+        } else {
+            Vector3::zeros()
+        };
+        let resistance_torque = rolling_twisting_torque(p_data, pair, &contact, history, dt);
+
+        history.cohesive_contact = p_data.cohesion_model == sphere::CohesionModel::Jkr;
+        history.liquid_bridge = p_data.surface_tension > 0.0;
+
+        let force = (normal.force - adhesive_force - dissipation_force) * normalized_delta
+            + tangential_force;
+
+        Some(ContactForces {
+            force,
+            torque_i: resistance_torque
+                - (contact_radius_i * normalized_delta).cross(&tangential_force),
+            torque_j: -resistance_torque
+                - (contact_radius_j * normalized_delta).cross(&tangential_force),
+            virial: force * delta_position.transpose(),
+            touching: true,
+        })
+    }
+}
+
+// Hertz normal contact without friction, the original model of this code
+pub struct Hertz;
+
+impl ContactModel for Hertz {
+    fn normal_contact(
+        &self,
+        p_data: &sphere::ParticleData,
+        pair: &Pair,
+        _history: &mut sphere::ContactHistory,
+        distance_delta: f64,
+    ) -> NormalContact {
+        hertz_normal_contact(p_data, pair, distance_delta)
+    }
+
+    fn tangential_stiffness(
+        &self,
+        _p_data: &sphere::ParticleData,
+        _pair: &Pair,
+        _contact_radius: f64,
+    ) -> f64 {
+        0.0
+    }
+}
+
+// Hertz normal contact with the Mindlin-Deresiewicz (no-slip) tangential stiffness
+pub struct HertzMindlin;
+
+impl ContactModel for HertzMindlin {
+    fn normal_contact(
+        &self,
+        p_data: &sphere::ParticleData,
+        pair: &Pair,
+        _history: &mut sphere::ContactHistory,
+        distance_delta: f64,
+    ) -> NormalContact {
+        hertz_normal_contact(p_data, pair, distance_delta)
+    }
+
+    fn tangential_stiffness(
+        &self,
+        p_data: &sphere::ParticleData,
+        pair: &Pair,
+        contact_radius: f64,
+    ) -> f64 {
+        8.0 * pair.interaction(p_data).eff_shear_mod * contact_radius
+    }
+}
+
+// Thornton (1997) elasto-plastic normal contact with the Mindlin-Deresiewicz tangential
+// stiffness. Hertz holds until the peak contact pressure reaches the limiting pressure p_y,
+// beyond which the force grows linearly as F_y + pi p_y R (delta - delta_y). Unloading
+// follows a Hertz curve of larger radius R_p offset by the permanent overlap delta_p, so the
+// energy lost to plastic work sets the restitution
+pub struct Thornton;
+
+impl ContactModel for Thornton {
+    fn normal_contact(
+        &self,
+        p_data: &sphere::ParticleData,
+        pair: &Pair,
+        history: &mut sphere::ContactHistory,
+        distance_delta: f64,
+    ) -> NormalContact {
+        let interaction = pair.interaction(p_data);
+        let yield_pressure = interaction.yield_pressure;
+
+        if yield_pressure <= 0.0 {
+            return hertz_normal_contact(p_data, pair, distance_delta);
+        }
+
+        history.max_overlap = history.max_overlap.max(distance_delta);
+
+        let effective_radius = effective_radius(p_data, pair);
+        let effective_youngs = interaction.eff_youngs_mod;
+
+        // Overlap and force at which the Hertz peak pressure 2 E* a / (pi R) reaches p_y
+        let yield_radius = PI * effective_radius * yield_pressure / (2.0 * effective_youngs);
+        let yield_overlap = yield_radius * yield_radius / effective_radius;
+        let yield_force = 4.0 / 3.0 * effective_youngs * yield_radius.powi(3) / effective_radius;
+
+        let max_overlap = history.max_overlap;
+
+        if max_overlap <= yield_overlap {
+            return hertz_normal_contact(p_data, pair, distance_delta);
+        }
+
+        if distance_delta >= max_overlap {
+            let plastic_stiffness = PI * yield_pressure * effective_radius;
+            return NormalContact {
+                force: yield_force + plastic_stiffness * (distance_delta - yield_overlap),
+                stiffness: plastic_stiffness,
+                contact_radius: (effective_radius * distance_delta).sqrt(),
+            };
+        }
+
+        let max_force =
+            yield_force + PI * yield_pressure * effective_radius * (max_overlap - yield_overlap);
+        let unloading_radius = 4.0 * effective_youngs / (3.0 * max_force)
+            * ((2.0 * max_force + yield_force) / (2.0 * PI * yield_pressure)).powf(1.5);
+        let plastic_overlap = max_overlap
+            - (3.0 * max_force / (4.0 * effective_youngs * unloading_radius.sqrt()))
+                .powf(2.0 / 3.0);
+
+        let elastic_overlap = (distance_delta - plastic_overlap).max(0.0);
+        let contact_radius = (unloading_radius * elastic_overlap).sqrt();
+        let contact_stiffness = 2.0 * effective_youngs * contact_radius;
+
+        NormalContact {
+            force: 2.0 / 3.0 * elastic_overlap * contact_stiffness,
+            stiffness: contact_stiffness,
+            contact_radius,
+        }
+    }
+
+    fn tangential_stiffness(
+        &self,
+        p_data: &sphere::ParticleData,
+        pair: &Pair,
+        contact_radius: f64,
+    ) -> f64 {
+        8.0 * pair.interaction(p_data).eff_shear_mod * contact_radius
+    }
+}
+
+// Linear spring-dashpot with constant normal and tangential stiffness
+pub struct Hooke {
+    pub normal_stiffness: f64,
+    pub tangential_stiffness: f64,
+}
+
+impl ContactModel for Hooke {
+    fn normal_contact(
+        &self,
+        p_data: &sphere::ParticleData,
+        pair: &Pair,
+        _history: &mut sphere::ContactHistory,
+        distance_delta: f64,
+    ) -> NormalContact {
+        NormalContact {
+            force: self.normal_stiffness * distance_delta,
+            stiffness: self.normal_stiffness,
+            contact_radius: (effective_radius(p_data, pair) * distance_delta.max(0.0)).sqrt(),
+        }
+    }
+
+    fn tangential_stiffness(
+        &self,
+        _p_data: &sphere::ParticleData,
+        _pair: &Pair,
+        _contact_radius: f64,
+    ) -> f64 {
+        self.tangential_stiffness
+    }
+
+    // Exact restitution for a linear spring, without the Hertz correction factor
+    fn damping(&self, beta: f64, stiffness: f64, reduced_mass: f64) -> f64 {
+        2.0 * beta * (stiffness * reduced_mass).sqrt()
+    }
+}
+
+// Linear interpolation of a user e(v_n) curve, held constant beyond its ends
+fn tabulated_restitution(velocities: &[f64], restitution: &[f64], impact_velocity: f64) -> f64 {
+    let upper = velocities.partition_point(|&velocity| velocity < impact_velocity);
+
+    if upper == 0 {
+        return restitution[0];
+    }
+    if upper == velocities.len() {
+        return restitution[velocities.len() - 1];
+    }
+
+    let fraction =
+        (impact_velocity - velocities[upper - 1]) / (velocities[upper] - velocities[upper - 1]);
+    restitution[upper - 1] + fraction * (restitution[upper] - restitution[upper - 1])
+}
+
+pub fn contact_model(contact_law: &sphere::ContactLaw) -> Box<dyn ContactModel> {
+    match contact_law {
+        sphere::ContactLaw::Hertz => Box::new(Hertz),
+        sphere::ContactLaw::HertzMindlin => Box::new(HertzMindlin),
+        sphere::ContactLaw::Thornton => Box::new(Thornton),
+        sphere::ContactLaw::Hooke {
+            normal_stiffness,
+            tangential_stiffness,
+        } => Box::new(Hooke {
+            normal_stiffness: *normal_stiffness,
+            tangential_stiffness: *tangential_stiffness,
+        }),
+    }
+}
+
+fn hertz_normal_contact(
+    p_data: &sphere::ParticleData,
+    pair: &Pair,
+    distance_delta: f64,
+) -> NormalContact {
+    let effective_radius = effective_radius(p_data, pair);
+    let contact_radius = (effective_radius * distance_delta.max(0.0)).sqrt();
+    let contact_stiffness = 2.0 * pair.interaction(p_data).eff_youngs_mod * contact_radius;
+
+    NormalContact {
+        force: 2.0 / 3.0 * distance_delta.max(0.0) * contact_stiffness,
+        stiffness: contact_stiffness,
+        contact_radius,
+    }
+}
+
+// Harmonic mean of the radii, the radius of i against a wall
+fn effective_radius(p_data: &sphere::ParticleData, pair: &Pair) -> f64 {
+    1.0 / (1.0 / p_data.radius[pair.i] + 1.0 / pair.radius_j(p_data))
+}
+
+// Quantities of an active contact shared by the normal, tangential and rotational models
+struct Contact {
+    normalized_delta: Vector3<f64>,
+    contact_radius: f64,
+    effective_radius: f64,
+    normal_stiffness: f64,
+    normal_force: f64,
+    friction: f64,
+    tangential_stiffness: f64,
+    tangential_damping: f64,
+}
+
+// Mindlin-Deresiewicz (no-slip) tangential spring-dashpot with a Coulomb sliding limit.
+// The stored displacement is that of j relative to i. Returns the tangential force acting on j.
+fn tangential_force(
+    contact: &Contact,
+    history: &mut sphere::ContactHistory,
+    tangential_velocity: Vector3<f64>,
+    dt: f64,
+) -> Vector3<f64> {
+    let normalized_delta = contact.normalized_delta;
+    let tangential_stiffness = contact.tangential_stiffness;
+    let tangential_damping = contact.tangential_damping;
+
+    // Rotate the stored displacement onto the current tangent plane, keeping its length
+    let mut displacement = rotate_onto_plane(history.tangential_displacement, normalized_delta);
+    displacement += tangential_velocity * dt;
+
+    let mut force = -tangential_stiffness * displacement - tangential_damping * tangential_velocity;
+
+    // Coulomb limit, the spring is reset to the length that gives the sliding force
+    let max_force = contact.friction * contact.normal_force;
+    if force.norm() > max_force {
+        force *= max_force / force.norm();
+        displacement = -(force + tangential_damping * tangential_velocity) / tangential_stiffness;
+    }
+
+    history.tangential_displacement = displacement;
+
+    force
+}
+
+// Rolling resistance (constant directional torque or elastic-plastic spring-dashpot, Ai et al.
+// 2011) plus Marshall twisting resistance. Returns the resistance torque acting on i, the
+// opposite torque acts on j.
+fn rolling_twisting_torque(
+    p_data: &sphere::ParticleData,
+    pair: &Pair,
+    contact: &Contact,
+    history: &mut sphere::ContactHistory,
+    dt: f64,
+) -> Vector3<f64> {
+    let i = pair.i;
+    let normalized_delta = contact.normalized_delta;
+
+    let rolling_friction = pair.interaction(p_data).rolling_friction;
+    let twisting_friction = pair.interaction(p_data).twisting_friction;
+
+    let relative_angular_velocity = p_data.angular_velocity[i] - pair.angular_velocity_j(p_data);
+    let twisting_velocity = relative_angular_velocity.dot(&normalized_delta) * normalized_delta;
+    let rolling_velocity = relative_angular_velocity - twisting_velocity;
+
+    let mut torque = Vector3::zeros();
+
+    // Rolling
+    let max_rolling_torque = rolling_friction * contact.effective_radius * contact.normal_force;
+    if rolling_friction > 0.0 {
+        match p_data.rolling_resistance {
+            sphere::RollingResistance::ConstantTorque => {
+                if rolling_velocity.norm() > 0.0 {
+                    torque -= max_rolling_torque * rolling_velocity / rolling_velocity.norm();
+                }
+            }
+            sphere::RollingResistance::SpringDashpot => {
+                let rolling_stiffness = 2.25
+                    * contact.normal_stiffness
+                    * rolling_friction
+                    * rolling_friction
+                    * contact.effective_radius
+                    * contact.effective_radius;
+
+                let inertia_i = p_data.moment_of_inertia[i]
+                    + p_data.mass[i] * p_data.radius[i] * p_data.radius[i];
+                let inertia_j = pair.moment_of_inertia_j(p_data)
+                    + pair.mass_j(p_data) * pair.radius_j(p_data) * pair.radius_j(p_data);
+                let rolling_inertia = 1.0 / (1.0 / inertia_i + 1.0 / inertia_j);
+                let rolling_damping =
+                    2.0 * p_data.rolling_damping * (rolling_inertia * rolling_stiffness).sqrt();
+
+                let mut spring_torque = rotate_onto_plane(history.rolling_torque, normalized_delta);
+                spring_torque -= rolling_stiffness * rolling_velocity * dt;
+
+                // Once fully mobilised the spring torque stays at the limit and damping is off
+                if spring_torque.norm() >= max_rolling_torque {
+                    if spring_torque.norm() > 0.0 {
+                        spring_torque *= max_rolling_torque / spring_torque.norm();
+                    }
+                    torque += spring_torque;
+                } else {
+                    torque += spring_torque - rolling_damping * rolling_velocity;
+                }
+
+                history.rolling_torque = spring_torque;
+            }
+        }
+    }
+
+    // Twisting
+    if twisting_friction > 0.0 {
+        let contact_radius = contact.contact_radius;
+        let twisting_stiffness =
+            0.5 * contact.tangential_stiffness * contact_radius * contact_radius;
+        let twisting_damping = 0.5 * contact.tangential_damping * contact_radius * contact_radius;
+        let max_twisting_torque =
+            2.0 / 3.0 * contact_radius * twisting_friction * contact.normal_force;
+
+        let mut spring_torque = history.twisting_torque.dot(&normalized_delta) * normalized_delta;
+        spring_torque -= twisting_stiffness * twisting_velocity * dt;
+
+        let mut twisting_torque = spring_torque - twisting_damping * twisting_velocity;
+        if twisting_torque.norm() > max_twisting_torque {
+            twisting_torque *= max_twisting_torque / twisting_torque.norm();
+            spring_torque = twisting_torque + twisting_damping * twisting_velocity;
+        }
+
+        history.twisting_torque = spring_torque;
+
+        torque += twisting_torque;
+    }
+
+    torque
+}
+
+// A liquid bridge between separated spheres, only the capillary pull acts on the pair
+fn liquid_bridge_forces(
+    p_data: &sphere::ParticleData,
+    pair: &Pair,
+    separation: f64,
+    effective_radius: f64,
+) -> ContactForces {
+    let normalized_delta = pair.delta_position / pair.delta_position.norm();
+
+    let force = -capillary_force(p_data, pair, separation, effective_radius) * normalized_delta;
+
+    ContactForces {
+        force,
+        torque_i: Vector3::zeros(),
+        torque_j: Vector3::zeros(),
+        virial: force * pair.delta_position.transpose(),
+        touching: false,
+    }
+}
+
+// Pendular bridge volume, each particle gives half of its liquid volume to the bridge and
+// walls are dry
+fn bridge_volume(p_data: &sphere::ParticleData, pair: &Pair) -> f64 {
+    match pair.j {
+        Body::Sphere(j) => 0.5 * (p_data.liquid_volume[pair.i] + p_data.liquid_volume[j]),
+        Body::Wall(_) => 0.5 * p_data.liquid_volume[pair.i],
+    }
+}
+
+fn bridge_contact_angle(p_data: &sphere::ParticleData, pair: &Pair) -> f64 {
+    let contact_angle_j = match pair.j {
+        Body::Sphere(j) => p_data.contact_angle[j],
+        Body::Wall(material) => p_data.materials[material].contact_angle,
+    };
+    0.5 * (p_data.contact_angle[pair.i] + contact_angle_j)
+}
+
+// Lian et al. (1993) rupture distance of a pendular bridge
+pub fn rupture_distance(volume: f64, contact_angle: f64) -> f64 {
+    (1.0 + 0.5 * contact_angle) * volume.cbrt()
+}
+
+// Rabinovich et al. (2005) capillary force magnitude at a surface separation (zero when
+// touching or overlapping) with the neck term neglected. Returns the attractive magnitude
+fn capillary_force(
+    p_data: &sphere::ParticleData,
+    pair: &Pair,
+    separation: f64,
+    effective_radius: f64,
+) -> f64 {
+    let volume = bridge_volume(p_data, pair);
+    if volume <= 0.0 {
+        return 0.0;
+    }
+
+    // Sphere radius for equal spheres, the harmonic mean radius otherwise and twice the sphere
+    // radius against a wall (the sphere-plate form)
+    let radius = 2.0 * effective_radius;
+    let max_force =
+        2.0 * PI * radius * p_data.surface_tension * bridge_contact_angle(p_data, pair).cos();
+
+    if separation <= 0.0 {
+        return max_force;
+    }
+
+    let immersion_height = 0.5
+        * separation
+        * (-1.0 + (1.0 + 2.0 * volume / (PI * radius * separation * separation)).sqrt());
+
+    max_force / (1.0 + separation / (2.0 * immersion_height))
+}
+
+// Gap beyond touching at which a JKR neck breaks under displacement control
+pub fn jkr_separation_distance(
+    effective_radius: f64,
+    effective_youngs: f64,
+    work_of_adhesion: f64,
+) -> f64 {
+    0.75 * (PI * PI * work_of_adhesion * work_of_adhesion * effective_radius
+        / (effective_youngs * effective_youngs))
+        .cbrt()
+}
+
+// Solves delta = a^2 / R - sqrt(2 pi w a / E) for the contact radius a on the stable branch of
+// the JKR curve. The function is convex and increasing there, so Newton iterations started
+// to the right of the root converge monotonically
+fn jkr_contact_radius(
+    distance_delta: f64,
+    effective_radius: f64,
+    effective_youngs: f64,
+    work_of_adhesion: f64,
+) -> f64 {
+    let adhesion = 2.0 * PI * work_of_adhesion / effective_youngs;
+    let overlap = |a: f64| a * a / effective_radius - (adhesion * a).sqrt() - distance_delta;
+
+    // Contact radius at the pull-off point, the lower end of the stable branch
+    let min_radius = (PI * work_of_adhesion * effective_radius * effective_radius
+        / (8.0 * effective_youngs))
+        .cbrt();
+
+    let mut contact_radius = (effective_radius * distance_delta.max(0.0)).sqrt() + min_radius;
+    while overlap(contact_radius) < 0.0 {
+        contact_radius *= 2.0;
+    }
+
+    for _ in 0..50 {
+        let slope =
+            2.0 * contact_radius / effective_radius - 0.5 * (adhesion / contact_radius).sqrt();
+        let step = overlap(contact_radius) / slope;
+        contact_radius -= step;
+        if contact_radius <= min_radius {
+            return min_radius;
+        }
+        if step.abs() < 1e-12 * contact_radius {
+            break;
+        }
+    }
+
+    contact_radius
+}
+
+// Rotates a stored history vector onto the current tangent plane of the contact, keeping its
+// length
+pub fn rotate_onto_plane(vector: Vector3<f64>, normal: Vector3<f64>) -> Vector3<f64> {
+    let length = vector.norm();
+    let projected = vector - vector.dot(&normal) * normal;
+    let projected_length = projected.norm();
+    if projected_length > 0.0 {
+        projected * (length / projected_length)
+    } else {
+        projected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADIUS: f64 = 0.005;
+
+    // Two equal glass spheres, with the contact properties mixed from their material
+    fn sphere_pair(surface_energy: f64, yield_pressure: f64) -> sphere::ParticleData {
+        let mass = 2500.0 * 4.0 / 3.0 * PI * RADIUS.powi(3);
+        let material = sphere::Material {
+            radius: RADIUS,
+            mass,
+            moment_of_inertia: 0.4 * mass * RADIUS * RADIUS,
+            youngs_mod: 8.7e9,
+            poisson_ratio: 0.3,
+            density: 2500.0,
+            rolling_friction: 0.0,
+            twisting_friction: 0.0,
+            surface_energy,
+            liquid_volume: 0.0,
+            contact_angle: 0.0,
+            yield_pressure,
+            id: 1,
+        };
+
+        let mut p_data = sphere::ParticleData::default();
+        p_data.materials.push(material.clone());
+        super::super::generate_interaction_table(&mut p_data);
+        for x in [0.0, 2.0 * RADIUS] {
+            p_data.push_particle(&material, Vector3::new(x, 0.0, 0.0), Vector3::zeros());
+        }
+        p_data
+    }
+
+    // The spheres overlapping by overlap along x, j approaching i at approach_speed
+    fn pair_at(overlap: f64, approach_speed: f64) -> Pair {
+        Pair {
+            i: 0,
+            j: Body::Sphere(1),
+            delta_position: Vector3::new(2.0 * RADIUS - overlap, 0.0, 0.0),
+            delta_velocity: Vector3::new(-approach_speed, 0.0, 0.0),
+        }
+    }
+
+    #[test]
+    fn jkr_force_follows_the_jkr_curve() {
+        let mut p_data = sphere_pair(0.05, 0.0);
+        p_data.cohesion_model = sphere::CohesionModel::Jkr;
+        let model = contact_model(&p_data.contact_law);
+
+        let interaction = p_data.interaction(0, 1);
+        let (work, youngs) = (interaction.work_of_adhesion, interaction.eff_youngs_mod);
+        let effective_radius = 0.5 * RADIUS;
+        let pull_off = 1.5 * PI * work * effective_radius;
+        let overlap = |a: f64| a * a / effective_radius - (2.0 * PI * work * a / youngs).sqrt();
+
+        // Net normal force on j along the branch from i, repulsive when positive
+        let normal_force = |overlap: f64| {
+            let mut history = sphere::ContactHistory {
+                cohesive_contact: true,
+                ..Default::default()
+            };
+            model
+                .evaluate(&p_data, &pair_at(overlap, 0.0), &mut history, 1e-8)
+                .map(|forces| forces.force[0])
+        };
+
+        // No load at the contact radius (9 pi w R^2 / 2 E)^1/3, and the largest pull, the
+        // pull-off force 3/2 pi w R, at (9 pi w R^2 / 8 E)^1/3
+        let unloaded = (4.5 * PI * work * effective_radius.powi(2) / youngs).cbrt();
+        assert!(normal_force(overlap(unloaded)).unwrap().abs() < 1e-6 * pull_off);
+        let largest_pull = (1.125 * PI * work * effective_radius.powi(2) / youngs).cbrt();
+        let force = normal_force(overlap(largest_pull)).unwrap();
+        assert!((force + pull_off).abs() < 1e-6 * pull_off);
+
+        // A neck holds the spheres together until pulled past the separation distance
+        let separation = jkr_separation_distance(effective_radius, youngs, work);
+        assert!(normal_force(-0.9 * separation).unwrap() < 0.0);
+        assert!(normal_force(-1.1 * separation).is_none());
+    }
+
+    #[test]
+    fn tabulated_restitution_sets_the_rebound_speed() {
+        let mut p_data = sphere_pair(0.0, 0.0);
+        // A linear spring, whose damping gives the restitution exactly
+        p_data.contact_law = sphere::ContactLaw::Hooke {
+            normal_stiffness: 1e5,
+            tangential_stiffness: 0.0,
+        };
+        p_data.restitution_model = sphere::RestitutionModel::Tabulated {
+            velocities: vec![0.5, 2.0],
+            restitution: vec![0.9, 0.5],
+        };
+        let model = contact_model(&p_data.contact_law);
+        let mass = p_data.mass[0];
+        let dt = 1e-7;
+
+        // Interpolated inside the table and held beyond its ends
+        for (impact_speed, restitution) in [(1.0, 0.9 - 0.4 / 3.0), (0.2, 0.9), (3.0, 0.5)] {
+            let (mut gap, mut approach_speed) = (1e-6, impact_speed);
+            let mut history = sphere::ContactHistory::default();
+            let mut touched = false;
+            loop {
+                match model.evaluate(&p_data, &pair_at(-gap, approach_speed), &mut history, dt) {
+                    // Equal and opposite forces on the two spheres
+                    Some(forces) => {
+                        touched = true;
+                        approach_speed -= 2.0 * forces.force[0] / mass * dt;
+                    }
+                    None if touched => break,
+                    None => {}
+                }
+                gap -= approach_speed * dt;
+            }
+
+            assert_eq!(history.impact_velocity, impact_speed);
+            assert!((-approach_speed / impact_speed - restitution).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn thornton_yields_and_unloads_elastically() {
+        let p_data = sphere_pair(0.0, 1e8);
+        let interaction = p_data.interaction(0, 1);
+        let (youngs, yield_pressure) = (interaction.eff_youngs_mod, interaction.yield_pressure);
+        let effective_radius = 0.5 * RADIUS;
+        let yield_radius = PI * effective_radius * yield_pressure / (2.0 * youngs);
+        let yield_overlap = yield_radius * yield_radius / effective_radius;
+
+        let mut history = sphere::ContactHistory::default();
+        let mut force = |overlap: f64| {
+            Thornton
+                .normal_contact(&p_data, &pair_at(overlap, 0.0), &mut history, overlap)
+                .force
+        };
+
+        // Hertz up to the yield overlap, then rising at pi p_y R without a jump
+        let hertz = |overlap: f64| 4.0 / 3.0 * youngs * (effective_radius * overlap.powi(3)).sqrt();
+        assert!((force(0.5 * yield_overlap) - hertz(0.5 * yield_overlap)).abs() < 1e-9);
+        let at_yield = force(yield_overlap);
+        assert!((at_yield - hertz(yield_overlap)).abs() < 1e-9 * at_yield);
+        let max_overlap = 4.0 * yield_overlap;
+        let loaded = force(max_overlap);
+        let slope = PI * yield_pressure * effective_radius;
+        assert!((loaded - at_yield - slope * 3.0 * yield_overlap).abs() < 1e-9 * loaded);
+
+        // Unloading starts from the same force, falls below Hertz and leaves a plastic overlap
+        assert!((force(max_overlap * (1.0 - 1e-9)) - loaded).abs() < 1e-6 * loaded);
+        assert!(force(0.5 * max_overlap) < hertz(0.5 * max_overlap));
+        assert_eq!(force(0.1 * yield_overlap), 0.0);
+
+        // Reloading is elastic back to the largest overlap
+        assert!((force(max_overlap) - loaded).abs() < 1e-9 * loaded);
+    }
+}
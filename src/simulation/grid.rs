@@ -1,80 +1,212 @@
-use nalgebra::Vector2;
+use nalgebra::{Matrix3, Vector3};
+use rayon::prelude::*;
 
+use super::contact;
 use crate::domain;
+use crate::mesh;
 use crate::sphere;
 
 pub fn relax_boundaries_box(d_data: &mut domain::DomainData, p_data: &mut sphere::ParticleData) {
     for i in 0..p_data.radius.len() {
-        if p_data.position[i][1] > d_data.domain[1] {
+        if d_data.periodic[1] && p_data.position[i][1] > d_data.domain[1] {
             // p_data.position[i][1] = d_data.domain[1] - p_data.radius[i]
             p_data.position[i][1] -= d_data.domain[1];
         }
         // if particle is less than domain move to end of domain
         // Also apply velocity change to particle for shearing
-        else if p_data.position[i][1] <= 0.0 {
+        else if d_data.periodic[1] && p_data.position[i][1] <= 0.0 {
             // p_data.position[i][1] = p_data.radius[i]
             p_data.position[i][1] += d_data.domain[1];
         }
         // std::cout << distb << std::endl;//X boundary condition
         // if particles is greater than domain move to beginning of domain
-        if p_data.position[i][0] > d_data.domain[0] {
+        if d_data.periodic[0] && p_data.position[i][0] > d_data.domain[0] {
             // p_data.position[i][0] = d_data.domain[0] - p_data.radius[i]
             p_data.position[i][0] -= d_data.domain[0];
         }
         // if particle is less than domain move to end of domain
-        else if p_data.position[i][0] <= 0.0 {
+        else if d_data.periodic[0] && p_data.position[i][0] <= 0.0 {
             // p_data.position[i][0] = p_data.radius[i]
             p_data.position[i][0] += d_data.domain[0];
         }
 
         // Z boundary condition
         // if particles is greater than domain move to beginning of domain
-        if p_data.position[i][2] > d_data.domain[2] {
+        if d_data.periodic[2] && p_data.position[i][2] > d_data.domain[2] {
             // p_data.position[i][2] = d_data.domain[2] - p_data.radius[i]
             p_data.position[i][2] -= d_data.domain[2];
         }
         // if particle is less than domain move to end of domain
-        else if p_data.position[i][2] <= 0.0 {
+        else if d_data.periodic[2] && p_data.position[i][2] <= 0.0 {
             // p_data.position[i][2] = p_data.radius[i]
             p_data.position[i][2] += d_data.domain[2];
         }
     }
 }
 
+// Bins each sphere once, as real in the box holding its centre and as ghost in every other box
+// within reach of it or of one of its periodic images
 pub fn update(d_data: &mut domain::DomainData, p_data: &mut sphere::ParticleData) {
     //Clears all boxes of particles in the box
-    for i in 0..d_data.g_data.len() {
-        for j in 0..d_data.g_data[i].len() {
-            for k in 0..d_data.g_data[i][j].len() {
-                d_data.g_data[i][j][k].real.clear();
-                d_data.g_data[i][j][k].ghost.clear();
-
-                for index in 0..p_data.radius.len() {
-                    if d_data.g_data[i][j][k].is_position_in_box(p_data.position[index]) {
-                        d_data.g_data[i][j][k].real.push(index.try_into().unwrap())
-                    } else if d_data.g_data[i][j][k].is_sphere_aabb_in_radius_enlarged_box(
-                        p_data.position[index],
-                        p_data.radius[index],
-                        p_data.max_radius,
-                    ) {
-                        d_data.g_data[i][j][k].ghost.push(index.try_into().unwrap())
-                    } else if ((i == 0 || i == d_data.g_data.len() - 1)
-                        || (j == 0 || j == d_data.g_data[i].len() - 1)
-                        || (k == 0 || k == d_data.g_data[i][j].len() - 1))
-                        && d_data.g_data[i][j][k].is_periodic_sphere(
-                            p_data.position[index],
-                            p_data.radius[index],
-                            p_data.max_radius,
-                            d_data,
-                        )
-                    {
-                        d_data.g_data[i][j][k].ghost.push(index.try_into().unwrap())
+    for plane in &mut d_data.g_data {
+        for column in plane {
+            for the_box in column {
+                the_box.real.clear();
+                the_box.ghost.clear();
+            }
+        }
+    }
+
+    // Far enough for the neighbor list to hold until the spheres close the skin
+    let reach = p_data.max_radius + p_data.interaction_range + d_data.neighbors.skin;
+    let mut images = Vec::new();
+    let mut ghost_boxes = Vec::new();
+
+    for index in 0..p_data.radius.len() {
+        let position = p_data.position[index];
+        let extent = p_data.radius[index] + reach;
+
+        let real_box = box_index(d_data, position);
+        if let Some([i, j, k]) = real_box {
+            d_data.g_data[i][j][k].real.push(index.try_into().unwrap());
+        }
+
+        // Images across a tilted axis are displaced along the axes before it, which are checked
+        // after it, as in periodic_pair
+        images.clear();
+        images.push(position);
+        for (order, &axis) in d_data.cell_order.iter().enumerate().rev() {
+            if !d_data.periodic[axis] {
+                continue;
+            }
+            for image_index in 0..images.len() {
+                let image: Vector3<f64> = images[image_index];
+                let mut shifted = Vec::new();
+                if image[axis] - extent <= 0.0 {
+                    shifted.push(image + d_data.lattice_vector(axis));
+                }
+                if image[axis] + extent >= d_data.domain[axis] {
+                    shifted.push(image - d_data.lattice_vector(axis));
+                }
+                // The tilt can carry an image out of the cell along the axes still to come,
+                // so it is brought back before they add their copies
+                for mut image in shifted {
+                    for &earlier in d_data.cell_order[..order].iter().rev() {
+                        if d_data.periodic[earlier] {
+                            image -= (image[earlier] / d_data.domain[earlier]).floor()
+                                * d_data.lattice_vector(earlier);
+                        }
+                    }
+                    images.push(image);
+                }
+            }
+        }
+
+        ghost_boxes.clear();
+        for image in &images {
+            let mut range = [(0, 0); 3];
+            for (axis, bounds) in range.iter_mut().enumerate() {
+                let boxes = d_data.collision_boxes[axis] as i64;
+                let length = d_data.domain[axis] / boxes as f64;
+                let lo = (((image[axis] - extent) / length).floor() as i64).max(0);
+                let hi = (((image[axis] + extent) / length).floor() as i64).min(boxes - 1);
+                *bounds = (lo, hi);
+            }
+            for i in range[0].0..=range[0].1 {
+                for j in range[1].0..=range[1].1 {
+                    for k in range[2].0..=range[2].1 {
+                        ghost_boxes.push([i as usize, j as usize, k as usize]);
+                    }
+                }
+            }
+        }
+
+        // Images reaching the same box are one ghost there
+        ghost_boxes.sort_unstable();
+        ghost_boxes.dedup();
+        for &[i, j, k] in &ghost_boxes {
+            if real_box != Some([i, j, k]) {
+                d_data.g_data[i][j][k].ghost.push(index.try_into().unwrap());
+            }
+        }
+    }
+
+    bin_triangles(d_data, p_data, false);
+}
+
+// Box holding a position, None outside the domain
+fn box_index(d_data: &domain::DomainData, position: Vector3<f64>) -> Option<[usize; 3]> {
+    let mut index = [0; 3];
+    for axis in 0..3 {
+        let length = d_data.domain[axis] / d_data.collision_boxes[axis] as f64;
+        let cell = (position[axis] / length).floor();
+        if cell < 0.0 || cell >= d_data.collision_boxes[axis] as f64 {
+            return None;
+        }
+        index[axis] = cell as usize;
+    }
+    Some(index)
+}
+
+// Box holding a position, with the spheres and triangles that can reach into it
+pub fn containing_box(d_data: &domain::DomainData, position: Vector3<f64>) -> Option<&domain::Box> {
+    let [i, j, k] = box_index(d_data, position)?;
+    Some(&d_data.g_data[i][j][k])
+}
+
+// Lists in each box the mesh triangles within reach of the spheres in it, visiting only the
+// boxes under the bounding box of each triangle. Every mesh is binned when the boxes are
+// rebuilt, in between only the moving meshes are binned again
+pub fn bin_triangles(
+    d_data: &mut domain::DomainData,
+    p_data: &sphere::ParticleData,
+    moving_only: bool,
+) {
+    // Spheres can leave their box by half the skin before the boxes are rebuilt
+    let reach = p_data.max_radius + p_data.interaction_range + d_data.neighbors.skin;
+    let reach = Vector3::new(reach, reach, reach);
+
+    let meshes = &d_data.meshes;
+    let is_binned = |mesh_index: usize| !moving_only || meshes[mesh_index].is_moving();
+    for column in &mut d_data.g_data {
+        for row in column.iter_mut() {
+            for the_box in row.iter_mut() {
+                the_box
+                    .triangles
+                    .retain(|&(mesh_index, _)| !is_binned(mesh_index));
+            }
+        }
+    }
+
+    for (mesh_index, mesh) in meshes.iter().enumerate() {
+        if !is_binned(mesh_index) {
+            continue;
+        }
+        for triangle in 0..mesh.triangles.len() {
+            let (lo, hi) = mesh.triangle_bounds(triangle);
+            let (lo, hi) = (lo - reach, hi + reach);
+
+            let mut range = [(0, 0); 3];
+            for (axis, bounds) in range.iter_mut().enumerate() {
+                let boxes = d_data.collision_boxes[axis] as i64;
+                let length = d_data.domain[axis] / boxes as f64;
+                let first = ((lo[axis] / length).floor() as i64).max(0);
+                let last = ((hi[axis] / length).floor() as i64).min(boxes - 1);
+                *bounds = (first, last);
+            }
+            for i in range[0].0..=range[0].1 {
+                for j in range[1].0..=range[1].1 {
+                    for k in range[2].0..=range[2].1 {
+                        d_data.g_data[i as usize][j as usize][k as usize]
+                            .triangles
+                            .push((mesh_index, triangle));
                     }
                 }
             }
         }
     }
 }
+
 pub fn relax(d_data: &mut domain::DomainData, p_data: &mut sphere::ParticleData, relax_rate: f64) {
     for box_i in 0..d_data.collision_boxes[0] {
         for box_j in 0..d_data.collision_boxes[1] {
@@ -134,20 +266,23 @@ pub fn relax(d_data: &mut domain::DomainData, p_data: &mut sphere::ParticleData,
                         let r1 = p_data.radius[i];
                         let r2 = p_data.radius[j];
 
-                        if p1[1] - r1 + d_data.domain[1] <= p2[1] + r2 {
+                        if d_data.periodic[1] && p1[1] - r1 + d_data.domain[1] <= p2[1] + r2 {
                             p1[1] += d_data.domain[1];
-                        } else if p2[1] - r2 + d_data.domain[1] <= p1[1] + r1 {
+                        } else if d_data.periodic[1] && p2[1] - r2 + d_data.domain[1] <= p1[1] + r1
+                        {
                             p2[1] += d_data.domain[1];
                         }
-                        if p1[0] - r1 + d_data.domain[0] <= p2[0] + r2 {
+                        if d_data.periodic[0] && p1[0] - r1 + d_data.domain[0] <= p2[0] + r2 {
                             p1[0] += d_data.domain[0];
-                        } else if p2[0] - r2 + d_data.domain[0] <= p1[0] + r1 {
+                        } else if d_data.periodic[0] && p2[0] - r2 + d_data.domain[0] <= p1[0] + r1
+                        {
                             p2[0] += d_data.domain[0];
                         }
 
-                        if p1[2] - r1 + d_data.domain[2] <= p2[2] + r2 {
+                        if d_data.periodic[2] && p1[2] - r1 + d_data.domain[2] <= p2[2] + r2 {
                             p1[2] += d_data.domain[2];
-                        } else if p2[2] - r2 + d_data.domain[2] <= p1[2] + r1 {
+                        } else if d_data.periodic[2] && p2[2] - r2 + d_data.domain[2] <= p1[2] + r1
+                        {
                             p2[2] += d_data.domain[2];
                         }
 
@@ -174,6 +309,38 @@ pub fn relax(d_data: &mut domain::DomainData, p_data: &mut sphere::ParticleData,
             }
         }
     }
+
+    // Spheres generated through a wall are pushed back in front of it
+    for wall in &d_data.walls {
+        for i in 0..p_data.radius.len() {
+            if wall.distance(p_data.position[i]) < p_data.radius[i] {
+                p_data.is_collision[i] = true;
+                p_data.velocity[i] += relax_rate * wall.normal_vector();
+            }
+        }
+    }
+
+    // and spheres cut by a mesh are pushed to the side of it their centre is on
+    for column in &d_data.g_data {
+        for row in column {
+            for the_box in row {
+                for &i in &the_box.real {
+                    let i = i as usize;
+                    for &(mesh_index, triangle) in &the_box.triangles {
+                        let [a, b, c] = d_data.meshes[mesh_index].triangles[triangle];
+                        let away = p_data.position[i]
+                            - mesh::closest_point_on_triangle(p_data.position[i], a, b, c);
+                        let distance = away.norm();
+
+                        if distance < p_data.radius[i] && distance > 0.0 {
+                            p_data.is_collision[i] = true;
+                            p_data.velocity[i] += relax_rate * away / distance;
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
 
 pub fn is_relaxed(
@@ -227,20 +394,23 @@ pub fn is_relaxed(
                         let r1 = p_data.radius[i];
                         let r2 = p_data.radius[j];
 
-                        if p1[1] - r1 + d_data.domain[1] <= p2[1] + r2 {
+                        if d_data.periodic[1] && p1[1] - r1 + d_data.domain[1] <= p2[1] + r2 {
                             p1[1] += d_data.domain[1];
-                        } else if p2[1] - r2 + d_data.domain[1] <= p1[1] + r1 {
+                        } else if d_data.periodic[1] && p2[1] - r2 + d_data.domain[1] <= p1[1] + r1
+                        {
                             p2[1] += d_data.domain[1];
                         }
-                        if p1[0] - r1 + d_data.domain[0] <= p2[0] + r2 {
+                        if d_data.periodic[0] && p1[0] - r1 + d_data.domain[0] <= p2[0] + r2 {
                             p1[0] += d_data.domain[0];
-                        } else if p2[0] - r2 + d_data.domain[0] <= p1[0] + r1 {
+                        } else if d_data.periodic[0] && p2[0] - r2 + d_data.domain[0] <= p1[0] + r1
+                        {
                             p2[0] += d_data.domain[0];
                         }
 
-                        if p1[2] - r1 + d_data.domain[2] <= p2[2] + r2 {
+                        if d_data.periodic[2] && p1[2] - r1 + d_data.domain[2] <= p2[2] + r2 {
                             p1[2] += d_data.domain[2];
-                        } else if p2[2] - r2 + d_data.domain[2] <= p1[2] + r1 {
+                        } else if d_data.periodic[2] && p2[2] - r2 + d_data.domain[2] <= p1[2] + r1
+                        {
                             p2[2] += d_data.domain[2];
                         }
 
@@ -256,377 +426,720 @@ pub fn is_relaxed(
             }
         }
     }
-    return true;
+
+    for wall in &d_data.walls {
+        for i in 0..p_data.radius.len() {
+            if wall.distance(p_data.position[i]) < radius_percentage * p_data.radius[i] {
+                return false;
+            }
+        }
+    }
+
+    for column in &d_data.g_data {
+        for row in column {
+            for the_box in row {
+                for &i in &the_box.real {
+                    let i = i as usize;
+                    for &(mesh_index, triangle) in &the_box.triangles {
+                        let [a, b, c] = d_data.meshes[mesh_index].triangles[triangle];
+                        let closest = mesh::closest_point_on_triangle(p_data.position[i], a, b, c);
+
+                        if (p_data.position[i] - closest).norm()
+                            < radius_percentage * p_data.radius[i]
+                        {
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    true
 }
 
 pub fn _simp_collisions(
     d_data: &domain::DomainData,
     p_data: &mut sphere::ParticleData,
     f_data: &mut sphere::ForceData,
-    _dt: f64,
-    ledisplace: f64,
+    model: &dyn contact::ContactModel,
+    dt: f64,
 ) {
     for i in 0..p_data.radius.len() {
         for j in i + 1..p_data.radius.len() {
-            let mut p1 = p_data.position[i];
-            let mut p2 = p_data.position[j];
-            let mut v1 = p_data.velocity[i];
-            let mut v2 = p_data.velocity[j];
-
-            let r1 = p_data.radius[i];
-            let r2 = p_data.radius[j];
-            if p1[1] - r1 + d_data.domain[1] <= p2[1] + r2 {
-                p1[1] += d_data.domain[1];
-                v1[0] += d_data.lees_edwards_boundary * d_data.domain[1];
-                p1[0] += ledisplace;
-                if p1[0] > d_data.domain[0] {
-                    p1[0] -= d_data.domain[0];
-                }
-            } else if p2[1] - r2 + d_data.domain[1] <= p1[1] + r1 {
-                p2[1] += d_data.domain[1];
-                v2[0] += d_data.lees_edwards_boundary * d_data.domain[1];
-                p2[0] += ledisplace;
-                if p2[0] > d_data.domain[0] {
-                    p2[0] -= d_data.domain[0];
-                }
-            }
-            if p1[0] - r1 + d_data.domain[0] <= p2[0] + r2 {
-                p1[0] += d_data.domain[0];
-            } else if p2[0] - r2 + d_data.domain[0] <= p1[0] + r1 {
-                p2[0] += d_data.domain[0];
+            let pair = periodic_pair(d_data, p_data, i, j);
+            if let Some((history, forces)) = evaluate_pair(p_data, model, &pair, dt) {
+                apply_pair(p_data, f_data, i, j, history, forces);
             }
+        }
+    }
+}
 
-            if p1[2] - r1 + d_data.domain[2] <= p2[2] + r2 {
-                p1[2] += d_data.domain[2];
-            } else if p2[2] - r2 + d_data.domain[2] <= p1[2] + r1 {
-                p2[2] += d_data.domain[2];
-            }
+pub fn collisions(
+    d_data: &domain::DomainData,
+    p_data: &mut sphere::ParticleData,
+    f_data: &mut sphere::ForceData,
+    model: &dyn contact::ContactModel,
+    dt: f64,
+) {
+    // Pairs are evaluated in parallel and their forces summed in list order, so the result is
+    // the same whatever the number of threads
+    let results: Vec<_> = d_data
+        .neighbors
+        .pairs
+        .par_iter()
+        .map(|&(i, j)| {
+            let pair = periodic_pair(d_data, p_data, i, j);
+            evaluate_pair(p_data, model, &pair, dt)
+        })
+        .collect();
+
+    for (&(i, j), result) in d_data.neighbors.pairs.iter().zip(results) {
+        if let Some((history, forces)) = result {
+            apply_pair(p_data, f_data, i, j, history, forces);
+        }
+    }
 
-            let delta_position = p2 - p1;
+    // Contacts that were not touched this step have separated, so their history is dropped
+    p_data.contacts.retain(|_, history| {
+        let in_contact = history.updated;
+        history.updated = false;
+        in_contact
+    });
+}
 
-            let distance = delta_position.norm();
+// Rebuilds the boxes and the neighbor list once two spheres could have closed the skin between
+// them, through their own displacements or the periodic images moving with the cell. Until then
+// only the moving meshes are binned again
+pub fn update_neighbors(d_data: &mut domain::DomainData, p_data: &mut sphere::ParticleData) {
+    if !is_rebuild_due(d_data, p_data) {
+        if d_data.meshes.iter().any(|mesh| mesh.is_moving()) {
+            bin_triangles(d_data, p_data, true);
+        }
+        return;
+    }
 
-            if distance < p_data.radius[i] + p_data.radius[j] {
-                p_data.is_collision[i] = true;
-                p_data.is_collision[j] = true;
+    update(d_data, p_data);
 
-                let normalized_delta = delta_position / distance;
+    // Pairs across boxes are seen from both boxes
+    let mut pairs = Vec::new();
+    for plane in &d_data.g_data {
+        for column in plane {
+            for the_box in column {
+                for (ii, &i) in the_box.real.iter().enumerate() {
+                    for &j in the_box.real[ii + 1..].iter().chain(&the_box.ghost) {
+                        pairs.push((i.min(j) as usize, i.max(j) as usize));
+                    }
+                }
+            }
+        }
+    }
+    pairs.sort_unstable();
+    pairs.dedup();
+
+    let reach = p_data.interaction_range + d_data.neighbors.skin;
+    pairs.retain(|&(i, j)| {
+        d_data
+            .nearest_image(p_data.position[j] - p_data.position[i])
+            .norm()
+            < p_data.radius[i] + p_data.radius[j] + reach
+    });
+
+    d_data.neighbors.pairs = pairs;
+    d_data.neighbors.positions = p_data.position.clone();
+    d_data.neighbors.lattice = Matrix3::from_columns(&[
+        d_data.lattice_vector(0),
+        d_data.lattice_vector(1),
+        d_data.lattice_vector(2),
+    ]);
+}
 
-                let distance_delta = (p_data.radius[i] + p_data.radius[j]) - distance;
+pub fn is_rebuild_due(d_data: &domain::DomainData, p_data: &sphere::ParticleData) -> bool {
+    let neighbors = &d_data.neighbors;
+    if neighbors.positions.len() != p_data.position.len() {
+        return true;
+    }
 
-                // let eff = p_data.sphere_material_map.get(&s).unwrap();
-                // let effective_radius = eff.eff_radius;
-                // let effective_youngs = eff.eff_youngs_mod;
+    let max_displacement = p_data
+        .position
+        .par_iter()
+        .zip(&neighbors.positions)
+        .map(|(position, built)| d_data.nearest_image(position - built).norm())
+        .reduce(|| 0.0, f64::max);
 
-                let effective_radius = 1.0 / (1.0 / p_data.radius[i] + 1.0 / p_data.radius[j]);
+    // Images across an axis move with its lattice vector, with the tilt for Lees-Edwards
+    let image_shift: f64 = (0..3)
+        .filter(|&axis| d_data.periodic[axis])
+        .map(|axis| (d_data.lattice_vector(axis) - neighbors.lattice.column(axis)).norm())
+        .sum();
 
-                let effective_youngs = 1.0
-                    / ((1.0 - p_data.poisson_ratio[i] * p_data.poisson_ratio[i])
-                        / p_data.youngs_mod[i]
-                        + (1.0 - p_data.poisson_ratio[j] * p_data.poisson_ratio[j])
-                            / p_data.youngs_mod[j]);
-                let normal_force = 4.0 / 3.0
-                    * effective_youngs
-                    * effective_radius.sqrt()
-                    * distance_delta.powf(3.0 / 2.0);
+    2.0 * max_displacement + image_shift > neighbors.skin
+}
 
-                let contact_stiffness =
-                    2.0 * effective_youngs * (effective_radius * distance_delta).sqrt();
-                let reduced_mass =
-                    p_data.mass[i] * p_data.mass[j] / (p_data.mass[i] + p_data.mass[j]);
+// Reorders the spheres along a Morton curve through the domain, so spheres close in space are
+// close in memory. The list is built again as every index has changed
+pub fn sort_particles(d_data: &mut domain::DomainData, p_data: &mut sphere::ParticleData) {
+    const BITS: u32 = 21;
+    let cells = (1u64 << BITS) as f64;
+
+    // Spreads the bits of a cell index to every third bit
+    let spread =
+        |cell: u64| (0..BITS).fold(0u64, |code, bit| code | ((cell >> bit) & 1) << (3 * bit));
+    let morton_code = |position: Vector3<f64>| {
+        (0..3).fold(0u64, |code, axis| {
+            let fraction = (position[axis] / d_data.domain[axis]).clamp(0.0, 1.0);
+            let cell = ((fraction * cells) as u64).min((1 << BITS) - 1);
+            code | spread(cell) << axis
+        })
+    };
+
+    let codes: Vec<u64> = p_data
+        .position
+        .par_iter()
+        .map(|&p| morton_code(p))
+        .collect();
+    let mut order: Vec<usize> = (0..codes.len()).collect();
+    order.sort_by_key(|&i| codes[i]);
+
+    p_data.reorder(&order);
+    d_data.neighbors.positions.clear();
+}
 
-                let delta_veloctiy = v2 - v1;
-                let f_dot = normalized_delta.dot(&delta_veloctiy);
-                let _v_r = f_dot * normalized_delta;
+// Builds the pair from the periodic (or Lees-Edwards) image of j nearest to i. Separated pairs
+// within the interaction range still need the nearest image
+pub fn periodic_pair(
+    d_data: &domain::DomainData,
+    p_data: &sphere::ParticleData,
+    i: usize,
+    j: usize,
+) -> contact::Pair {
+    let mut p1 = p_data.position[i];
+    let mut p2 = p_data.position[j];
+    let mut v1 = p_data.velocity[i];
+    let mut v2 = p_data.velocity[j];
+
+    let r1 = p_data.radius[i];
+    let r2 = p_data.radius[j] + p_data.interaction_range;
+
+    // Images across a tilted axis are displaced and moving along the axes before it, which are
+    // checked after it. An image displaced past the cell is first brought back into it
+    for &axis in d_data.cell_order.iter().rev() {
+        if !d_data.periodic[axis] {
+            continue;
+        }
+        if p1[axis] > d_data.domain[axis] {
+            p1 -= d_data.lattice_vector(axis);
+            v1 -= d_data.image_velocity(axis);
+        }
+        if p2[axis] > d_data.domain[axis] {
+            p2 -= d_data.lattice_vector(axis);
+            v2 -= d_data.image_velocity(axis);
+        }
+        if p1[axis] - r1 + d_data.domain[axis] <= p2[axis] + r2 {
+            p1 += d_data.lattice_vector(axis);
+            v1 += d_data.image_velocity(axis);
+        } else if p2[axis] - r2 + d_data.domain[axis] <= p1[axis] + r1 {
+            p2 += d_data.lattice_vector(axis);
+            v2 += d_data.image_velocity(axis);
+        }
+    }
 
-                // std::cout << beta <<F_dot << " " << reduced_mass << " "<< contact_stiffness << "\n";
+    contact::Pair {
+        i,
+        j: contact::Body::Sphere(j),
+        delta_position: p2 - p1,
+        delta_velocity: v2 - v1,
+    }
+}
 
-                let dissipation_force = 2.0
-                    * 0.9128709292
-                    * p_data.beta
-                    * (contact_stiffness * reduced_mass).sqrt()
-                    * f_dot;
+// The contact forces of a pair and its history to store, leaving the spheres untouched so
+// pairs can be evaluated side by side. History is stored keyed by (min index, max index) in the
+// orientation of the smaller index, and flipped so the contact model always sees it from i
+fn evaluate_pair(
+    p_data: &sphere::ParticleData,
+    model: &dyn contact::ContactModel,
+    pair: &contact::Pair,
+    dt: f64,
+) -> Option<(sphere::ContactHistory, contact::ContactForces)> {
+    let i = pair.i;
+    let contact::Body::Sphere(j) = pair.j else {
+        unreachable!("wall contacts are handled by wall_collisions");
+    };
+
+    let orientation = if i < j { 1.0 } else { -1.0 };
+    let mut history = p_data
+        .contacts
+        .get(&(i.min(j), i.max(j)))
+        .cloned()
+        .unwrap_or_default()
+        .oriented(orientation);
+
+    let forces = model.evaluate(p_data, pair, &mut history, dt)?;
+
+    history.updated = true;
+    Some((history.oriented(orientation), forces))
+}
 
-                // if(distance_delta > 0.00001)
-                // std::cout << dissipation_force << " " << normal_force << " "<< distance_delta <<"\n";
+fn apply_pair(
+    p_data: &mut sphere::ParticleData,
+    f_data: &mut sphere::ForceData,
+    i: usize,
+    j: usize,
+    history: sphere::ContactHistory,
+    forces: contact::ContactForces,
+) {
+    p_data.contacts.insert((i.min(j), i.max(j)), history);
 
-                p_data.force[i] -= (normal_force - dissipation_force) * normalized_delta;
-                p_data.force[j] += (normal_force - dissipation_force) * normalized_delta;
+    if forces.touching {
+        p_data.is_collision[i] = true;
+        p_data.is_collision[j] = true;
+    }
 
-                f_data
-                    .force
-                    .push((normal_force - dissipation_force) * normalized_delta);
-                f_data.particle_indexes.push(Vector2::new(i, j));
-                f_data.del.push(delta_position);
+    p_data.force[i] -= forces.force;
+    p_data.force[j] += forces.force;
 
-                // if(distance_delta > 10e-7){
-                //     std::cout << distance_delta << " " << switched << "\n";
-                // }
+    p_data.torque[i] += forces.torque_i;
+    p_data.torque[j] += forces.torque_j;
 
-                // if(switched){
-                //     if(delta_veloctiy.norm() > 0.1){
-                //         std::cout << delta_veloctiy[0] << " " << delta_veloctiy[1] << " " << delta_veloctiy[2] << "\n";
-                //         std::cout << delta_veloctiy.norm() << "\n";
-                //         std::cout << error << "\n";
+    f_data.forcedata.push(forces.virial);
+}
 
-                //     }
+// Deforms the cell through one step of the velocity gradient. The stretch is applied to the
+// positions and collision boxes, while the tilt grows with the shear and is remapped onto an
+// equivalent cell once an image has been displaced by a whole cell edge
+pub fn deform_cell(
+    d_data: &mut domain::DomainData,
+    p_data: &mut sphere::ParticleData,
+    velocity_gradient: Matrix3<f64>,
+    dt: f64,
+) {
+    let mut cell = d_data.tilt;
+    cell.set_diagonal(&d_data.domain);
+    let mut tilt_rate = velocity_gradient * cell;
+    tilt_rate.fill_diagonal(0.0);
+    d_data.tilt += tilt_rate * dt;
+
+    for axis in 0..3 {
+        if velocity_gradient[(axis, axis)] != 0.0 {
+            rescale_axis(
+                d_data,
+                p_data,
+                axis,
+                1.0 + velocity_gradient[(axis, axis)] * dt,
+            );
+        }
+    }
 
-                // }
+    // Subtracting the lattice vector of an earlier axis gives the same lattice, and tilts the
+    // column along the axes before that one, so those come after it
+    let order = d_data.cell_order;
+    for later in 1..3 {
+        for earlier in (0..later).rev() {
+            let (axis, along) = (order[later], order[earlier]);
+            let cells = (d_data.tilt[(along, axis)] / d_data.domain[along]).floor();
+            if cells != 0.0 {
+                let shift = d_data.lattice_vector(along) * cells;
+                for component in 0..3 {
+                    if component != axis {
+                        d_data.tilt[(component, axis)] -= shift[component];
+                    }
+                }
             }
         }
     }
 }
 
-pub fn collisions(
-    d_data: &domain::DomainData,
+// Stretches the cell along an axis by factor, moving the spheres and collision boxes with it
+fn rescale_axis(
+    d_data: &mut domain::DomainData,
+    p_data: &mut sphere::ParticleData,
+    axis: usize,
+    factor: f64,
+) {
+    for position in &mut p_data.position {
+        position[axis] *= factor;
+    }
+
+    d_data.domain[axis] *= factor;
+    d_data.domain_volume = d_data.domain[0] * d_data.domain[1] * d_data.domain[2];
+    for plane in &mut d_data.g_data {
+        for column in plane {
+            for the_box in column {
+                the_box.lo[axis] *= factor;
+                the_box.hi[axis] *= factor;
+            }
+        }
+    }
+
+    p_data.volume_fraction /= factor;
+}
+
+// Moves every wall through one time step starting at time
+pub fn move_walls(d_data: &mut domain::DomainData, time: f64, dt: f64) {
+    let domain = d_data.domain;
+    for wall in &mut d_data.walls {
+        wall.move_wall(domain, time, dt);
+    }
+}
+
+// Contacts of the spheres with the planar walls, using the same contact model. The wall
+// force is summed for the wall stress
+pub fn wall_collisions(
+    d_data: &mut domain::DomainData,
     p_data: &mut sphere::ParticleData,
     f_data: &mut sphere::ForceData,
-    _dt: f64,
-    ledisplace: f64,
+    model: &dyn contact::ContactModel,
+    dt: f64,
 ) {
-    for box_i in 0..d_data.collision_boxes[0] {
-        for box_j in 0..d_data.collision_boxes[1] {
-            for box_k in 0..d_data.collision_boxes[2] {
-                for ii in 0..d_data.g_data[box_i as usize][box_j as usize][box_k as usize]
-                    .real
-                    .len()
-                {
-                    for jj in ii + 1
-                        ..d_data.g_data[box_i as usize][box_j as usize][box_k as usize]
-                            .real
-                            .len()
-                    {
-                        let i = d_data.g_data[box_i as usize][box_j as usize][box_k as usize].real
-                            [ii] as usize;
-                        let j = d_data.g_data[box_i as usize][box_j as usize][box_k as usize].real
-                            [jj] as usize;
+    for (wall_index, wall) in d_data.walls.iter_mut().enumerate() {
+        let normal = wall.normal_vector();
+        wall.force = Vector3::zeros();
+
+        for i in 0..p_data.radius.len() {
+            // A centre behind the wall has passed through it, and its overlap would be bogus
+            let distance = wall.distance(p_data.position[i]);
+            if distance < 0.0 || distance > p_data.radius[i] + p_data.interaction_range {
+                continue;
+            }
 
-                        // println!("i {}, j {}", i,j);
-                        let delta_position = p_data.position[j] - p_data.position[i];
+            let pair = contact::Pair {
+                i,
+                j: contact::Body::Wall(wall.material),
+                delta_position: -distance * normal,
+                delta_velocity: wall.velocity - p_data.velocity[i],
+            };
 
-                        let distance = delta_position.norm();
+            let key = (i, wall_index);
+            let mut history = p_data.wall_contacts.get(&key).cloned().unwrap_or_default();
 
-                        if distance < p_data.radius[i] + p_data.radius[j] {
-                            p_data.is_collision[i] = true;
-                            p_data.is_collision[j] = true;
+            let Some(forces) = model.evaluate(p_data, &pair, &mut history, dt) else {
+                continue;
+            };
 
-                            let normalized_delta = delta_position / distance;
+            history.updated = true;
+            p_data.wall_contacts.insert(key, history);
 
-                            let distance_delta = (p_data.radius[i] + p_data.radius[j]) - distance;
-
-                            // let eff = p_data.sphere_material_map.get(&s).unwrap();
-                            // let effective_radius = eff.eff_radius;
-                            // let effective_youngs = eff.eff_youngs_mod;
-
-                            let effective_radius =
-                                1.0 / (1.0 / p_data.radius[i] + 1.0 / p_data.radius[j]);
-
-                            let effective_youngs = 1.0
-                                / ((1.0 - p_data.poisson_ratio[i] * p_data.poisson_ratio[i])
-                                    / p_data.youngs_mod[i]
-                                    + (1.0 - p_data.poisson_ratio[j] * p_data.poisson_ratio[j])
-                                        / p_data.youngs_mod[j]);
-
-                            let contact_stiffness =
-                                2.0 * effective_youngs * (effective_radius * distance_delta).sqrt();
-
-                            let normal_force = 2.0 / 3.0 * distance_delta * contact_stiffness;
-                            let reduced_mass =
-                                p_data.mass[i] * p_data.mass[j] / (p_data.mass[i] + p_data.mass[j]);
-
-                            let delta_veloctiy = p_data.velocity[j] - p_data.velocity[i];
-                            let f_dot = normalized_delta.dot(&delta_veloctiy);
-                            let v_r_n = f_dot * normalized_delta;
-
-                            let dissipation_force = 2.0
-                                * 0.91287092917
-                                * p_data.beta
-                                * (contact_stiffness * reduced_mass).sqrt()
-                                * v_r_n.norm()
-                                * v_r_n.dot(&normalized_delta).signum();
-
-                            // println!("{} {} {}", distance_delta, normal_force, dissipation_force);
-                            p_data.force[i] -=
-                                (normal_force - dissipation_force) * normalized_delta;
-                            p_data.force[j] +=
-                                (normal_force - dissipation_force) * normalized_delta;
-
-                            let force_length_matrix = ((normal_force - dissipation_force)
-                                * normalized_delta)
-                                * delta_position.transpose();
-                            f_data.forcedata.push(force_length_matrix);
-                        }
-                    }
-                }
+            if forces.touching {
+                p_data.is_collision[i] = true;
+            }
 
-                for ii in 0..d_data.g_data[box_i as usize][box_j as usize][box_k as usize]
-                    .real
-                    .len()
-                {
-                    for jj in 0..d_data.g_data[box_i as usize][box_j as usize][box_k as usize]
-                        .ghost
-                        .len()
-                    {
-                        let i = d_data.g_data[box_i as usize][box_j as usize][box_k as usize].real
-                            [ii] as usize;
-                        let j = d_data.g_data[box_i as usize][box_j as usize][box_k as usize].ghost
-                            [jj] as usize;
-                        let mut p1 = p_data.position[i];
-                        let mut p2 = p_data.position[j];
-                        let mut v1 = p_data.velocity[i];
-                        let mut v2 = p_data.velocity[j];
+            p_data.force[i] -= forces.force;
+            p_data.torque[i] += forces.torque_i;
+            wall.force += forces.force;
 
-                        let r1 = p_data.radius[i];
-                        let r2 = p_data.radius[j];
-                        if p1[1] - r1 + d_data.domain[1] <= p2[1] + r2 {
-                            p1[1] += d_data.domain[1];
-                            v1[0] += d_data.lees_edwards_boundary * d_data.domain[1];
-                            p1[0] += ledisplace;
-                            if p1[0] > d_data.domain[0] {
-                                p1[0] -= d_data.domain[0];
-                            }
-                        } else if p2[1] - r2 + d_data.domain[1] <= p1[1] + r1 {
-                            p2[1] += d_data.domain[1];
-                            v2[0] += d_data.lees_edwards_boundary * d_data.domain[1];
-                            p2[0] += ledisplace;
-                            if p2[0] > d_data.domain[0] {
-                                p2[0] -= d_data.domain[0];
-                            }
+            f_data.forcedata.push(forces.virial);
+        }
+
+        wall.force_sum += wall.force;
+    }
+
+    p_data.wall_contacts.retain(|_, history| {
+        let in_contact = history.updated;
+        history.updated = false;
+        in_contact
+    });
+}
+
+// Contacts of the spheres with the mesh triangles found through the boxes, using the same
+// contact model as walls. A sphere on an edge or vertex shared by several triangles finds the
+// same closest point on each, so only the first of coincident contact points is kept
+pub fn mesh_collisions(
+    d_data: &mut domain::DomainData,
+    p_data: &mut sphere::ParticleData,
+    f_data: &mut sphere::ForceData,
+    model: &dyn contact::ContactModel,
+    dt: f64,
+) {
+    let domain::DomainData { g_data, meshes, .. } = d_data;
+
+    for column in g_data.iter() {
+        for row in column {
+            for the_box in row {
+                for &i in &the_box.real {
+                    let i = i as usize;
+                    let position = p_data.position[i];
+                    let reach = p_data.radius[i] + p_data.interaction_range;
+
+                    let mut candidates = Vec::new();
+                    for &(mesh_index, triangle) in &the_box.triangles {
+                        let [a, b, c] = meshes[mesh_index].triangles[triangle];
+                        let closest = mesh::closest_point_on_triangle(position, a, b, c);
+                        let distance = (closest - position).norm();
+                        if distance <= reach && distance > 0.0 {
+                            candidates.push((distance, closest, mesh_index, triangle));
                         }
-                        if p1[0] - r1 + d_data.domain[0] <= p2[0] + r2 {
-                            p1[0] += d_data.domain[0];
-                        } else if p2[0] - r2 + d_data.domain[0] <= p1[0] + r1 {
-                            p2[0] += d_data.domain[0];
+                    }
+                    candidates.sort_by(|first, second| first.0.total_cmp(&second.0));
+
+                    let mut contact_points: Vec<Vector3<f64>> = Vec::new();
+                    for (_, closest, mesh_index, triangle) in candidates {
+                        if contact_points
+                            .iter()
+                            .any(|point| (point - closest).norm() < 1e-9 * p_data.radius[i])
+                        {
+                            continue;
                         }
+                        contact_points.push(closest);
 
-                        if p1[2] - r1 + d_data.domain[2] <= p2[2] + r2 {
-                            p1[2] += d_data.domain[2];
-                        } else if p2[2] - r2 + d_data.domain[2] <= p1[2] + r1 {
-                            p2[2] += d_data.domain[2];
-                        }
+                        let mesh = &mut meshes[mesh_index];
+                        let pair = contact::Pair {
+                            i,
+                            j: contact::Body::Wall(mesh.material),
+                            delta_position: closest - position,
+                            delta_velocity: mesh.velocity_at(closest) - p_data.velocity[i],
+                        };
 
-                        let delta_position = p2 - p1;
+                        let key = (i, mesh_index, triangle);
+                        let mut history =
+                            p_data.mesh_contacts.get(&key).cloned().unwrap_or_default();
 
-                        let distance = delta_position.norm();
+                        let Some(forces) = model.evaluate(p_data, &pair, &mut history, dt) else {
+                            continue;
+                        };
 
-                        if distance < p_data.radius[i] + p_data.radius[j] {
+                        history.updated = true;
+                        p_data.mesh_contacts.insert(key, history);
+
+                        if forces.touching {
                             p_data.is_collision[i] = true;
-                            p_data.is_collision[j] = true;
+                        }
 
-                            let normalized_delta = delta_position / distance;
+                        p_data.force[i] -= forces.force;
+                        p_data.torque[i] += forces.torque_i;
+                        mesh.force += forces.force;
+                        mesh.torque += (closest - mesh.center).cross(&forces.force);
 
-                            let distance_delta = (p_data.radius[i] + p_data.radius[j]) - distance;
-
-                            let effective_radius =
-                                1.0 / (1.0 / p_data.radius[i] + 1.0 / p_data.radius[j]);
-
-                            let effective_youngs = 1.0
-                                / ((1.0 - p_data.poisson_ratio[i] * p_data.poisson_ratio[i])
-                                    / p_data.youngs_mod[i]
-                                    + (1.0 - p_data.poisson_ratio[j] * p_data.poisson_ratio[j])
-                                        / p_data.youngs_mod[j]);
-
-                            let contact_stiffness =
-                                2.0 * effective_youngs * (effective_radius * distance_delta).sqrt();
-
-                            let normal_force = 2.0 / 3.0 * distance_delta * contact_stiffness;
-                            let reduced_mass =
-                                p_data.mass[i] * p_data.mass[j] / (p_data.mass[i] + p_data.mass[j]);
-
-                            let delta_veloctiy = v2 - v1;
-                            let f_dot = normalized_delta.dot(&delta_veloctiy);
-                            let v_r_n = f_dot * normalized_delta;
-
-                            let dissipation_force = 2.0
-                                * 0.9128709292
-                                * p_data.beta
-                                * (contact_stiffness * reduced_mass).sqrt()
-                                * v_r_n.norm()
-                                * v_r_n.dot(&normalized_delta).signum();
-                            // println!("{} {} {}", distance_delta, normal_force, dissipation_force);
-                            p_data.force[i] -=
-                                (normal_force - dissipation_force) * normalized_delta * 0.5;
-                            p_data.force[j] +=
-                                (normal_force - dissipation_force) * normalized_delta * 0.5;
-
-                            let force_length_matrix =
-                                ((normal_force - dissipation_force) * normalized_delta * 0.5)
-                                    * delta_position.transpose();
-                            f_data.forcedata.push(force_length_matrix);
-                        }
+                        f_data.forcedata.push(forces.virial);
                     }
                 }
             }
         }
     }
+
+    p_data.mesh_contacts.retain(|_, history| {
+        let in_contact = history.updated;
+        history.updated = false;
+        in_contact
+    });
 }
 
 pub fn _euler_integration(p_data: &mut sphere::ParticleData, dt: f64) {
     for i in 0..p_data.radius.len() {
-        p_data.velocity[i] += dt * p_data.force[i] / p_data.mass[i];
+        let acceleration = p_data.force[i] / p_data.mass[i] + p_data.body_acceleration(i);
+        p_data.velocity[i] += dt * acceleration;
         p_data.position[i] += p_data.velocity[i] * dt;
+
+        p_data.angular_velocity[i] += dt * p_data.torque[i] / p_data.moment_of_inertia[i];
     }
 }
 
+// Body forces are evaluated at the start of the step here and at the end in final_integrate
 pub fn inital_integrate(p_data: &mut sphere::ParticleData, dt: f64) {
-    for i in 0..p_data.radius.len() {
-        p_data.velocity[i] += 0.5 * dt * p_data.force[i] / p_data.mass[i];
-        p_data.position[i] += p_data.velocity[i] * dt;
-    }
+    let acceleration = accelerations(p_data);
+    p_data
+        .velocity
+        .par_iter_mut()
+        .zip(&mut p_data.position)
+        .zip(&acceleration)
+        .for_each(|((velocity, position), acceleration)| {
+            *velocity += 0.5 * dt * acceleration;
+            *position += *velocity * dt;
+        });
+    spin_up(p_data, dt);
 }
 
 pub fn final_integrate(p_data: &mut sphere::ParticleData, dt: f64) {
-    for i in 0..p_data.radius.len() {
-        p_data.velocity[i] += 0.5 * dt * p_data.force[i] / p_data.mass[i];
-    }
+    let acceleration = accelerations(p_data);
+    p_data
+        .velocity
+        .par_iter_mut()
+        .zip(&acceleration)
+        .for_each(|(velocity, acceleration)| *velocity += 0.5 * dt * acceleration);
+    spin_up(p_data, dt);
 }
 
-pub fn lees_edwards_boundaries(
-    d_data: &domain::DomainData,
-    p_data: &mut sphere::ParticleData,
-    _dt: f64,
-    ledisplace: f64,
-) {
+fn accelerations(p_data: &sphere::ParticleData) -> Vec<Vector3<f64>> {
+    (0..p_data.radius.len())
+        .into_par_iter()
+        .map(|i| p_data.force[i] / p_data.mass[i] + p_data.body_acceleration(i))
+        .collect()
+}
+
+// Half step of the angular velocities
+fn spin_up(p_data: &mut sphere::ParticleData, dt: f64) {
+    p_data
+        .angular_velocity
+        .par_iter_mut()
+        .zip(&p_data.torque)
+        .zip(&p_data.moment_of_inertia)
+        .for_each(|((angular_velocity, torque), moment_of_inertia)| {
+            *angular_velocity += 0.5 * dt * torque / *moment_of_inertia;
+        });
+}
+
+// Wraps the spheres leaving a periodic axis back into the cell through the lattice vector,
+// changing their velocity by the shear across it
+pub fn periodic_boundaries(d_data: &domain::DomainData, p_data: &mut sphere::ParticleData) {
     for i in 0..p_data.radius.len() {
-        // Y boundary condition
-        // if particles is greater than domain move to beginning of domain
-        // Also apply velocity change to particle for shearing
-        if p_data.position[i][1] > d_data.domain[1] {
-            p_data.position[i][1] -= d_data.domain[1];
-            p_data.velocity[i][0] -= d_data.lees_edwards_boundary * d_data.domain[1];
-            p_data.position[i][0] -= ledisplace;
-            if p_data.position[i][0] <= 0.0 {
-                p_data.position[i][0] += d_data.domain[0];
+        // Wrapping across a tilted axis moves the sphere along the axes before it, which are
+        // wrapped after it
+        for &axis in d_data.cell_order.iter().rev() {
+            // if particles is greater than domain move to beginning of domain
+            if d_data.periodic[axis] && p_data.position[i][axis] > d_data.domain[axis] {
+                p_data.position[i] -= d_data.lattice_vector(axis);
+                p_data.velocity[i] -= d_data.image_velocity(axis);
             }
-        }
-        // if particle is less than domain move to end of domain
-        // Also apply velocity change to particle for shearing
-        else if p_data.position[i][1] <= 0.0 {
-            p_data.position[i][1] += d_data.domain[1];
-            p_data.velocity[i][0] += d_data.lees_edwards_boundary * d_data.domain[1];
-            p_data.position[i][0] += ledisplace;
-            if p_data.position[i][0] > d_data.domain[0] {
-                p_data.position[i][0] -= d_data.domain[0];
+            // if particle is less than domain move to end of domain
+            else if d_data.periodic[axis] && p_data.position[i][axis] <= 0.0 {
+                p_data.position[i] += d_data.lattice_vector(axis);
+                p_data.velocity[i] += d_data.image_velocity(axis);
             }
         }
-        // std::cout << distb << std::endl;//X boundary condition
-        // if particles is greater than domain move to beginning of domain
-        if p_data.position[i][0] > d_data.domain[0] {
-            p_data.position[i][0] -= d_data.domain[0];
-        }
-        // if particle is less than domain move to end of domain
-        else if p_data.position[i][0] <= 0.0 {
-            p_data.position[i][0] += d_data.domain[0];
-        }
+    }
+}
 
-        // Z boundary condition
-        // if particles is greater than domain move to beginning of domain
-        if p_data.position[i][2] > d_data.domain[2] {
-            p_data.position[i][2] -= d_data.domain[2];
+#[cfg(test)]
+mod tests {
+    use core::f64::consts::PI;
+
+    use super::*;
+
+    #[test]
+    fn sphere_behind_wall_is_not_pushed() {
+        let radius = 0.01;
+        let mass = 2500.0 * 4.0 / 3.0 * PI * radius * radius * radius;
+        let material = sphere::Material {
+            radius,
+            mass,
+            moment_of_inertia: 0.4 * mass * radius * radius,
+            youngs_mod: 8.7e9,
+            poisson_ratio: 0.3,
+            density: 2500.0,
+            rolling_friction: 0.0,
+            twisting_friction: 0.0,
+            surface_energy: 0.0,
+            liquid_volume: 0.0,
+            contact_angle: 0.0,
+            yield_pressure: 0.0,
+            id: 1,
+        };
+
+        let mut p_data = sphere::ParticleData::default();
+        p_data.materials.push(material.clone());
+        super::super::generate_interaction_table(&mut p_data);
+        // Overlapping in front of the wall, just behind it and far behind it
+        for y in [0.5 + 0.5 * radius, 0.5 - 0.5 * radius, 0.5 - 3.0 * radius] {
+            p_data.push_particle(&material, Vector3::new(0.5, y, 0.5), Vector3::zeros());
         }
-        // if particle is less than domain move to end of domain
-        else if p_data.position[i][2] <= 0.0 {
-            p_data.position[i][2] += d_data.domain[2];
+
+        let mut d_data = domain::DomainData::default();
+        d_data.walls.push(domain::Wall {
+            axis: 1,
+            position: 0.5,
+            normal: 1.0,
+            prescribed_velocity: Vector3::zeros(),
+            velocity: Vector3::zeros(),
+            motion: domain::WallMotion::Constant,
+            displacement: Vector3::zeros(),
+            material: 0,
+            force: Vector3::zeros(),
+            force_sum: Vector3::zeros(),
+        });
+
+        let mut f_data = sphere::ForceData {
+            particle_indexes: Vec::new(),
+            force: Vec::new(),
+            del: Vec::new(),
+            forcedata: Vec::new(),
+        };
+        let model = contact::contact_model(&p_data.contact_law);
+        wall_collisions(&mut d_data, &mut p_data, &mut f_data, model.as_ref(), 1e-8);
+
+        assert!(p_data.force[0][1] > 0.0);
+        assert_eq!(p_data.force[1], Vector3::zeros());
+        assert_eq!(p_data.force[2], Vector3::zeros());
+        assert!(!p_data.is_collision[1] && !p_data.is_collision[2]);
+        assert_eq!(p_data.wall_contacts.keys().collect::<Vec<_>>(), [&(0, 0)]);
+    }
+
+    #[test]
+    fn corner_contact_across_tilted_cell_is_found() {
+        let radius = 0.03;
+        let mass = 2500.0 * 4.0 / 3.0 * PI * radius * radius * radius;
+        let material = sphere::Material {
+            radius,
+            mass,
+            moment_of_inertia: 0.4 * mass * radius * radius,
+            youngs_mod: 8.7e9,
+            poisson_ratio: 0.3,
+            density: 2500.0,
+            rolling_friction: 0.0,
+            twisting_friction: 0.0,
+            surface_energy: 0.0,
+            liquid_volume: 0.0,
+            contact_angle: 0.0,
+            yield_pressure: 0.0,
+            id: 1,
+        };
+
+        let mut p_data = sphere::ParticleData::default();
+        p_data.materials.push(material.clone());
+        super::super::generate_interaction_table(&mut p_data);
+        // Touching only through the image across y, which the tilt carries almost a cell along x
+        p_data.push_particle(&material, Vector3::new(0.995, 0.01, 0.5), Vector3::zeros());
+        p_data.push_particle(&material, Vector3::new(0.005, 0.99, 0.5), Vector3::zeros());
+        p_data.max_radius = radius;
+
+        let mut d_data = domain::DomainData::default();
+        d_data.tilt[(0, 1)] = 0.97;
+        d_data.collision_boxes = Vector3::new(4, 4, 1);
+        d_data.g_data = (0..4)
+            .map(|_| {
+                (0..4)
+                    .map(|_| {
+                        vec![domain::Box {
+                            real: Vec::new(),
+                            ghost: Vec::new(),
+                            triangles: Vec::new(),
+                            lo: Vector3::zeros(),
+                            hi: Vector3::zeros(),
+                        }]
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let separation = periodic_pair(&d_data, &p_data, 0, 1).delta_position;
+        assert!((separation.norm() - 0.0447).abs() < 1e-4);
+        let nearest = d_data.nearest_image(p_data.position[1] - p_data.position[0]);
+        assert!((nearest - separation).norm() < 1e-12);
+
+        update_neighbors(&mut d_data, &mut p_data);
+        assert_eq!(d_data.neighbors.pairs.len(), 1);
+    }
+
+    #[test]
+    fn tilt_is_remapped_onto_the_same_lattice() {
+        let mut p_data = sphere::ParticleData::default();
+        let mut sheared = domain::DomainData::default();
+        let mut gradient = Matrix3::zeros();
+        gradient[(0, 1)] = 1.0;
+
+        // Forward past a whole cell edge, and backward past zero
+        for (start, rate, remapped) in [(0.99, 1.0, 0.01), (0.005, -1.0, 0.985)] {
+            sheared.tilt[(0, 1)] = start;
+            deform_cell(&mut sheared, &mut p_data, rate * gradient, 0.02);
+            assert!((sheared.tilt[(0, 1)] - remapped).abs() < 1e-12);
+
+            let mut unmapped = domain::DomainData::default();
+            unmapped.tilt[(0, 1)] = start + rate * 0.02;
+            for delta in [
+                Vector3::new(0.3, 0.45, 0.1),
+                Vector3::new(-0.2, -0.6, 0.3),
+                Vector3::new(0.49, 0.51, -0.4),
+            ] {
+                let difference = sheared.nearest_image(delta) - unmapped.nearest_image(delta);
+                assert!(difference.norm() < 1e-12);
+            }
         }
     }
 }
@@ -0,0 +1,239 @@
+use core::f64::consts::PI;
+use std::process;
+
+use nalgebra::Vector3;
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+
+use super::grid;
+use crate::domain;
+use crate::mesh;
+use crate::sphere;
+
+// INSERT x_min y_min z_min x_max y_max z_max rate material_id followed by optional keywords with
+// their values. rate is in spheres per unit time from this point of the run
+pub fn add_inserter(
+    d_data: &mut domain::DomainData,
+    p_data: &mut sphere::ParticleData,
+    results: &[&str],
+) {
+    if results.len() < 9 {
+        println!("INSERT needs a region, a rate and a material");
+        process::exit(1);
+    }
+    let value = |index: usize| results[index].parse::<f64>().unwrap();
+    let material_index = |id: &str| {
+        let id = id.parse::<i32>().unwrap();
+        match p_data.materials.iter().position(|m| m.id == id) {
+            Some(index) => index,
+            None => {
+                println!("INSERT uses unknown material {}", id);
+                process::exit(1);
+            }
+        }
+    };
+
+    let mut inserter = domain::Inserter {
+        lo: Vector3::new(value(1), value(2), value(3)),
+        hi: Vector3::new(value(4), value(5), value(6)),
+        rate: value(7),
+        materials: vec![(material_index(results[8]), 1.0)],
+        radius_range: None,
+        velocity: Vector3::zeros(),
+        remaining: None,
+        due: 0.0,
+        full: false,
+    };
+
+    let mut index = 9;
+    while index < results.len() {
+        let count = match results[index] {
+            "TOTAL" => 1,
+            "MATERIAL" | "RADIUS" => 2,
+            "VELOCITY" => 3,
+            _ => {
+                println!("Unknown INSERT property {}", results[index]);
+                process::exit(1);
+            }
+        };
+        if index + count >= results.len() {
+            println!("INSERT property {} needs {} values", results[index], count);
+            process::exit(1);
+        }
+
+        match results[index] {
+            "TOTAL" => inserter.remaining = Some(results[index + 1].parse::<usize>().unwrap()),
+            "MATERIAL" => {
+                if value(index + 2) <= 0.0 {
+                    println!("INSERT material weights must be positive");
+                    process::exit(1);
+                }
+                inserter
+                    .materials
+                    .push((material_index(results[index + 1]), value(index + 2)))
+            }
+            "RADIUS" => inserter.radius_range = Some((value(index + 1), value(index + 2))),
+            _ => {
+                inserter.velocity =
+                    Vector3::new(value(index + 1), value(index + 2), value(index + 3))
+            }
+        }
+        index += count + 1;
+    }
+
+    // The neighbor search reaches as far as the largest sphere
+    for &(material, _) in &inserter.materials {
+        let radius = match inserter.radius_range {
+            Some((_, max_radius)) => max_radius,
+            None => p_data.materials[material].radius,
+        };
+        p_data.max_radius = p_data.max_radius.max(radius);
+    }
+
+    d_data.inserters.push(inserter);
+}
+
+// Inserts the spheres due this step at random places in each region where they touch nothing.
+// When no free place is found the region is full, and one sphere waits for the spheres to move
+// far enough for the boxes to be rebuilt before the region is tried again
+pub fn insert_particles(
+    d_data: &mut domain::DomainData,
+    p_data: &mut sphere::ParticleData,
+    dt: f64,
+) {
+    let mut rng = rand::thread_rng();
+
+    for inserter in &mut d_data.inserters {
+        inserter.due += inserter.rate * dt;
+    }
+    let is_due = |inserter: &domain::Inserter| inserter.due >= 1.0 && inserter.remaining != Some(0);
+    if !d_data.inserters.iter().any(is_due) {
+        return;
+    }
+
+    // The boxes built with the neighbor list hold every sphere that can reach them until it is
+    // due a rebuild, which is also when a full region may have room again
+    let is_stale = grid::is_rebuild_due(d_data, p_data);
+    if is_stale {
+        for inserter in &mut d_data.inserters {
+            inserter.full = false;
+        }
+    }
+    if !d_data
+        .inserters
+        .iter()
+        .any(|inserter| is_due(inserter) && !inserter.full)
+    {
+        return;
+    }
+    if is_stale {
+        grid::update(d_data, p_data);
+    } else if d_data.meshes.iter().any(|mesh| mesh.is_moving()) {
+        grid::bin_triangles(d_data, p_data, true);
+    }
+    // The spheres inserted below are checked one by one
+    let binned = p_data.radius.len();
+
+    for index in 0..d_data.inserters.len() {
+        while is_due(&d_data.inserters[index]) && !d_data.inserters[index].full {
+            let inserter = &d_data.inserters[index];
+
+            // Material drawn by weight, and radius from the range at the material density
+            let weights =
+                WeightedIndex::new(inserter.materials.iter().map(|&(_, weight)| weight)).unwrap();
+            let mut material =
+                p_data.materials[inserter.materials[weights.sample(&mut rng)].0].clone();
+            if let Some((min_radius, max_radius)) = inserter.radius_range {
+                let radius = min_radius + (max_radius - min_radius) * rng.gen::<f64>();
+                material.radius = radius;
+                material.mass = material.density * 4.0 / 3.0 * PI * radius.powi(3);
+                material.moment_of_inertia = 0.4 * material.mass * radius * radius;
+            }
+
+            let mut position = None;
+            for _attempt in 0..100 {
+                let candidate = Vector3::from_fn(|axis, _| {
+                    let lo = inserter.lo[axis] + material.radius;
+                    let hi = inserter.hi[axis] - material.radius;
+                    if hi > lo {
+                        lo + (hi - lo) * rng.gen::<f64>()
+                    } else {
+                        0.5 * (inserter.lo[axis] + inserter.hi[axis])
+                    }
+                });
+                if is_free(d_data, p_data, binned, candidate, material.radius) {
+                    position = Some(candidate);
+                    break;
+                }
+            }
+            let Some(position) = position else {
+                let inserter = &mut d_data.inserters[index];
+                inserter.due = inserter.due.min(1.0);
+                inserter.full = true;
+                break;
+            };
+
+            p_data.push_particle(&material, position, inserter.velocity);
+            d_data.neighbors.positions.clear();
+            p_data.volume_fraction +=
+                4.0 / 3.0 * PI * material.radius.powi(3) / d_data.domain_volume;
+
+            let inserter = &mut d_data.inserters[index];
+            inserter.due -= 1.0;
+            if let Some(remaining) = &mut inserter.remaining {
+                *remaining -= 1;
+            }
+        }
+    }
+}
+
+// Whether a sphere at position would overlap no sphere (through the nearest periodic image),
+// wall or mesh. Only the spheres and triangles binned in the box holding the position can reach
+// it, along with the spheres inserted since the boxes were filled
+fn is_free(
+    d_data: &domain::DomainData,
+    p_data: &sphere::ParticleData,
+    binned: usize,
+    position: Vector3<f64>,
+    radius: f64,
+) -> bool {
+    let overlaps = |j: usize| {
+        let delta = d_data.nearest_image(p_data.position[j] - position);
+        delta.norm() < radius + p_data.radius[j]
+    };
+    let touches = |[a, b, c]: [Vector3<f64>; 3]| {
+        (position - mesh::closest_point_on_triangle(position, a, b, c)).norm() < radius
+    };
+
+    if (binned..p_data.radius.len()).any(overlaps) {
+        return false;
+    }
+    if d_data
+        .walls
+        .iter()
+        .any(|wall| wall.distance(position) < radius)
+    {
+        return false;
+    }
+
+    match grid::containing_box(d_data, position) {
+        Some(the_box) => {
+            !the_box
+                .real
+                .iter()
+                .chain(&the_box.ghost)
+                .any(|&j| overlaps(j as usize))
+                && !the_box
+                    .triangles
+                    .iter()
+                    .any(|&(mesh, triangle)| touches(d_data.meshes[mesh].triangles[triangle]))
+        }
+        None => {
+            !(0..binned).any(overlaps)
+                && !d_data
+                    .meshes
+                    .iter()
+                    .any(|mesh| mesh.triangles.iter().any(|&triangle| touches(triangle)))
+        }
+    }
+}
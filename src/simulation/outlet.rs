@@ -0,0 +1,53 @@
+use core::f64::consts::PI;
+use std::process;
+
+use nalgebra::Vector3;
+
+use crate::domain;
+use crate::sphere;
+
+// OUTLET x_min y_min z_min x_max y_max z_max, from this point of the run
+pub fn add_outlet(d_data: &mut domain::DomainData, results: &[&str]) {
+    if results.len() < 7 {
+        println!("OUTLET needs a region");
+        process::exit(1);
+    }
+    let value = |index: usize| results[index].parse::<f64>().unwrap();
+
+    d_data.outlets.push(domain::Outlet {
+        lo: Vector3::new(value(1), value(2), value(3)),
+        hi: Vector3::new(value(4), value(5), value(6)),
+        count: 0,
+        mass: 0.0,
+        total_mass: 0.0,
+    });
+}
+
+// Deletes the spheres with their centres in an outlet, adding their mass to it
+pub fn remove_outlet_particles(d_data: &mut domain::DomainData, p_data: &mut sphere::ParticleData) {
+    if d_data.outlets.is_empty() {
+        return;
+    }
+
+    let mut removed = Vec::new();
+    for i in 0..p_data.radius.len() {
+        let position = p_data.position[i];
+        let outlet = d_data.outlets.iter_mut().find(|outlet| {
+            (0..3)
+                .all(|axis| position[axis] >= outlet.lo[axis] && position[axis] <= outlet.hi[axis])
+        });
+        if let Some(outlet) = outlet {
+            outlet.count += 1;
+            outlet.mass += p_data.mass[i];
+            outlet.total_mass += p_data.mass[i];
+            p_data.volume_fraction -=
+                4.0 / 3.0 * PI * p_data.radius[i].powi(3) / d_data.domain_volume;
+            removed.push(i);
+        }
+    }
+
+    if !removed.is_empty() {
+        p_data.remove_particles(removed);
+        d_data.neighbors.positions.clear();
+    }
+}
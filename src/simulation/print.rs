@@ -1,6 +1,7 @@
 use std::{fs::File, io::Write};
 use nalgebra::Matrix3;
 
+use crate::domain;
 use crate::sphere;
 // Prints positions of particles to a vtp file in the vtp folder (no check is done for opening file, must include folder or no printing)
 pub fn print_vtp(p_data: &mut sphere::ParticleData, count: i32)
@@ -9,10 +10,10 @@ pub fn print_vtp(p_data: &mut sphere::ParticleData, count: i32)
     let mut file = File::create(filename).unwrap();
 
     // Write a &str in the file (ignoring the result).
-    write!(&mut file, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<VTKFile type=\"PolyData\" version=\"0.1\" byte_order=\"LittleEndian\">\n<PolyData>\n").unwrap();
+    writeln!(&mut file, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<VTKFile type=\"PolyData\" version=\"0.1\" byte_order=\"LittleEndian\">\n<PolyData>").unwrap();
     
     
-    write!(&mut file, "<Piece NumberOfPoints=\"{}\">\n",p_data.radius.len()).unwrap();
+    writeln!(&mut file, "<Piece NumberOfPoints=\"{}\">",p_data.radius.len()).unwrap();
 
     write!(&mut file, "<Points>").unwrap();
 
@@ -21,28 +22,60 @@ pub fn print_vtp(p_data: &mut sphere::ParticleData, count: i32)
     for i in 0..p_data.radius.len() {
        writeln!(&mut file, "{} {} {}",p_data.position[i][0],p_data.position[i][1],p_data.position[i][2] ).unwrap();
     }
-     write!(&mut file, "</DataArray>\n").unwrap();
-     write!(&mut file, "</Points>\n").unwrap();
+     writeln!(&mut file, "</DataArray>").unwrap();
+     writeln!(&mut file, "</Points>").unwrap();
 
-     write!(&mut file, "<PointData Scalars=\"\" Vectors=\"\">\n").unwrap();
-     write!(&mut file, "<DataArray type=\"Float32\" Name=\"Radius\" format=\"ascii\">\n").unwrap();
+     writeln!(&mut file, "<PointData Scalars=\"\" Vectors=\"\">").unwrap();
+     // Stable id of each sphere, the index changes as spheres are sorted or removed
+     writeln!(&mut file, "<DataArray type=\"Int32\" Name=\"Id\" format=\"ascii\">").unwrap();
+    for i in 0..p_data.radius.len() {
+         writeln!(&mut file, "{}",p_data.id[i]).unwrap();
+    }
+     writeln!(&mut file, "</DataArray>").unwrap();
+
+     writeln!(&mut file, "<DataArray type=\"Float32\" Name=\"Radius\" format=\"ascii\">").unwrap();
     for i in 0..p_data.radius.len() {
          writeln!(&mut file, "{}",p_data.radius[i]).unwrap();
     }
 
+     writeln!(&mut file, "</DataArray>").unwrap();
+
+     writeln!(&mut file, "<DataArray type=\"Float32\" Name=\"AngularVelocity\" NumberOfComponents=\"3\" format=\"ascii\">").unwrap();
+    for i in 0..p_data.radius.len() {
+         writeln!(&mut file, "{} {} {}",p_data.angular_velocity[i][0],p_data.angular_velocity[i][1],p_data.angular_velocity[i][2]).unwrap();
+    }
+     writeln!(&mut file, "</DataArray>").unwrap();
+
+     writeln!(&mut file, "<DataArray type=\"Float32\" Name=\"Torque\" NumberOfComponents=\"3\" format=\"ascii\">").unwrap();
+    for i in 0..p_data.radius.len() {
+         writeln!(&mut file, "{} {} {}",p_data.torque[i][0],p_data.torque[i][1],p_data.torque[i][2]).unwrap();
+    }
+     writeln!(&mut file, "</DataArray>").unwrap();
+
+     // Number of intact bonds on each sphere, showing the agglomerates
+     let mut bond_count = vec![0; p_data.radius.len()];
+     for &(i, j) in p_data.bonds.keys() {
+         bond_count[i] += 1;
+         bond_count[j] += 1;
+     }
+     writeln!(&mut file, "<DataArray type=\"Int32\" Name=\"Bonds\" format=\"ascii\">").unwrap();
+    for count in bond_count {
+         writeln!(&mut file, "{}",count).unwrap();
+    }
      write!(&mut file, "</DataArray>").unwrap();
  
-     write!(&mut file, "</PointData>\n</Piece>\n</PolyData>\n</VTKFile>\n").unwrap();
+     writeln!(&mut file, "</PointData>\n</Piece>\n</PolyData>\n</VTKFile>").unwrap();
 
 }
 
-pub fn print_stress(kinetic_tensor: Matrix3<f64>, collision_tensor: Matrix3<f64>, count: i32)
+// The components are taken in the flow (f) and gradient (g) axes of the shear, as ff gf gg
+pub fn print_stress(d_data: &domain::DomainData, kinetic_tensor: Matrix3<f64>, collision_tensor: Matrix3<f64>, count: i32)
 {
+    let (f, g) = (d_data.flow_axis, d_data.gradient_axis);
    
 
      let mut file = match File::options()
                             .read(true)
-                            .write(true)
                             .append(true)
                             .open("stress.txt") {
         Ok(file) => {
@@ -55,5 +88,119 @@ pub fn print_stress(kinetic_tensor: Matrix3<f64>, collision_tensor: Matrix3<f64>
         }
     };
 
-    writeln!(&mut file,"{} {} {} {} {} {} {} {} {} {}",count,kinetic_tensor.index((0,0)),kinetic_tensor.index((1,0)), kinetic_tensor.index((1,1)),collision_tensor.index((0,0)),collision_tensor.index((1,0)), collision_tensor.index((1,1)),kinetic_tensor.index((0,0)).abs() + collision_tensor.index((0,0)).abs(),kinetic_tensor.index((1,0)).abs() + collision_tensor.index((1,0)).abs(), kinetic_tensor.index((1,1)).abs() + collision_tensor.index((1,1)).abs()).ok();
-   }
\ No newline at end of file
+    writeln!(&mut file,"{} {} {} {} {} {} {} {} {} {}",count,kinetic_tensor.index((f,f)),kinetic_tensor.index((g,f)), kinetic_tensor.index((g,g)),collision_tensor.index((f,f)),collision_tensor.index((g,f)), collision_tensor.index((g,g)),kinetic_tensor.index((f,f)).abs() + collision_tensor.index((f,f)).abs(),kinetic_tensor.index((g,f)).abs() + collision_tensor.index((g,f)).abs(), kinetic_tensor.index((g,g)).abs() + collision_tensor.index((g,g)).abs()).ok();
+   }
+
+// Appends the intact and broken bond counts to bonds.txt, created on first use
+pub fn print_bonds(p_data: &sphere::ParticleData, count: i32)
+{
+     let mut file = match File::options()
+                            .create(true)
+                            .append(true)
+                            .open("bonds.txt") {
+        Ok(file) => {
+            file
+        }
+        Err(err) => {
+
+            println!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    writeln!(&mut file,"{} {} {}",count,p_data.bonds.len(),p_data.broken_bonds).ok();
+}
+
+// Appends the displacement of each wall, the force of the spheres on it and that force per unit
+// wall area, averaged over the stress interval, to walls.txt
+pub fn print_walls(d_data: &domain::DomainData, average_count: i32, count: i32)
+{
+     let mut file = match File::options()
+                            .create(true)
+                            .append(true)
+                            .open("walls.txt") {
+        Ok(file) => {
+            file
+        }
+        Err(err) => {
+
+            println!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    for (index, wall) in d_data.walls.iter().enumerate() {
+        let force = wall.force_sum / average_count as f64;
+        let stress = force / wall.area(d_data.domain);
+        writeln!(&mut file,"{} {} {} {} {} {} {} {} {} {} {}",count,index,wall.displacement[0],wall.displacement[1],wall.displacement[2],force[0],force[1],force[2],stress[0],stress[1],stress[2]).ok();
+    }
+}
+
+// Appends the force of the spheres on each mesh and its torque about the mesh center,
+// averaged over the stress interval, to meshes.txt
+pub fn print_meshes(d_data: &domain::DomainData, average_count: i32, count: i32)
+{
+     let mut file = match File::options()
+                            .create(true)
+                            .append(true)
+                            .open("meshes.txt") {
+        Ok(file) => {
+            file
+        }
+        Err(err) => {
+
+            println!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    for (index, mesh) in d_data.meshes.iter().enumerate() {
+        let force = mesh.force / average_count as f64;
+        let torque = mesh.torque / average_count as f64;
+        writeln!(&mut file,"{} {} {} {} {} {} {} {}",count,index,force[0],force[1],force[2],torque[0],torque[1],torque[2]).ok();
+    }
+}
+
+// Appends the storage and loss moduli and the third harmonic ratio I3/I1 of each whole period
+// of oscillatory shear to moduli.txt
+pub fn print_moduli(moduli: (f64, f64, f64), period: i64, count: i32)
+{
+     let mut file = match File::options()
+                            .create(true)
+                            .append(true)
+                            .open("moduli.txt") {
+        Ok(file) => {
+            file
+        }
+        Err(err) => {
+
+            println!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    writeln!(&mut file,"{} {} {} {} {}",count,period,moduli.0,moduli.1,moduli.2).ok();
+}
+
+// Appends the spheres and mass each outlet removed over the interval, the discharge rate and
+// the total mass removed to outlets.txt
+pub fn print_outlets(d_data: &domain::DomainData, interval: f64, count: i32)
+{
+     let mut file = match File::options()
+                            .create(true)
+                            .append(true)
+                            .open("outlets.txt") {
+        Ok(file) => {
+            file
+        }
+        Err(err) => {
+
+            println!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    for (index, outlet) in d_data.outlets.iter().enumerate() {
+        writeln!(&mut file,"{} {} {} {} {} {}",count,index,outlet.count,outlet.mass,outlet.mass / interval,outlet.total_mass).ok();
+    }
+}
@@ -13,17 +13,150 @@ macro_rules! pub_struct {
 pub_struct!(Material {
     radius: f64,
     mass: f64,
+    moment_of_inertia: f64,
     youngs_mod: f64,
     poisson_ratio: f64,
     density: f64,
+    rolling_friction: f64,
+    twisting_friction: f64,
+    surface_energy: f64,
+    liquid_volume: f64,
+    contact_angle: f64,
+    // Limiting contact pressure of the elasto-plastic law, zero for a perfectly elastic material
+    yield_pressure: f64,
     id: i32,
 });
 
-pub_struct!(EffectiveMaterialPreCalc {
-    eff_radius: f64,
+// Contact properties of a pair of materials. Built from mixing rules before the run and
+// overridable with the PAIR command
+pub_struct!(InteractionPair {
     eff_youngs_mod: f64,
+    eff_shear_mod: f64,
+    beta: f64,
+    friction: f64,
+    rolling_friction: f64,
+    twisting_friction: f64,
+    work_of_adhesion: f64,
+    yield_pressure: f64,
+});
+
+pub_struct!(ContactHistory {
+    tangential_displacement: Vector3<f64>,
+    rolling_torque: Vector3<f64>,
+    twisting_torque: Vector3<f64>,
+    cohesive_contact: bool,
+    liquid_bridge: bool,
+    impact_velocity: f64,
+    // Largest overlap reached, which sets the plastic unloading curve
+    max_overlap: f64,
+    updated: bool,
 });
 
+impl Default for ContactHistory {
+    fn default() -> Self {
+        ContactHistory {
+            tangential_displacement: Vector3::zeros(),
+            rolling_torque: Vector3::zeros(),
+            twisting_torque: Vector3::zeros(),
+            cohesive_contact: false,
+            liquid_bridge: false,
+            impact_velocity: 0.0,
+            max_overlap: 0.0,
+            updated: false,
+        }
+    }
+}
+
+impl ContactHistory {
+    // Flips the pair antisymmetric quantities when the pair is seen from the other particle
+    pub fn oriented(mut self, orientation: f64) -> Self {
+        self.tangential_displacement *= orientation;
+        self.rolling_torque *= orientation;
+        self.twisting_torque *= orientation;
+        self
+    }
+}
+
+// Parallel bond (Potyondy and Cundall 2004), a cylinder of cement between two spheres acting
+// alongside their contact. Loads are those acting on the larger index sphere, in the frame of
+// the smaller one. Stiffnesses are per unit bond area
+pub_struct!(Bond {
+    radius: f64,
+    length: f64,
+    normal_stiffness: f64,
+    shear_stiffness: f64,
+    tensile_strength: f64,
+    shear_strength: f64,
+    shear_force: Vector3<f64>,
+    bending_moment: Vector3<f64>,
+    twisting_moment: Vector3<f64>,
+});
+
+impl Bond {
+    // The same bond seen with its spheres swapped, whose loads act the other way
+    pub fn flipped(mut self) -> Self {
+        self.shear_force = -self.shear_force;
+        self.bending_moment = -self.bending_moment;
+        self.twisting_moment = -self.twisting_moment;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContactLaw {
+    Hertz,
+    HertzMindlin,
+    Thornton,
+    Hooke {
+        normal_stiffness: f64,
+        tangential_stiffness: f64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestitutionModel {
+    Constant,
+    Viscoelastic {
+        dissipative_constant: f64,
+    },
+    Tabulated {
+        velocities: Vec<f64>,
+        restitution: Vec<f64>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CohesionModel {
+    None,
+    Jkr,
+    Dmt,
+    Linear,
+}
+
+// External acceleration field acting on every sphere
+#[derive(Debug, Clone, PartialEq)]
+pub enum BodyForce {
+    Uniform {
+        acceleration: Vector3<f64>,
+    },
+    // acceleration + gradient * position
+    Gradient {
+        acceleration: Vector3<f64>,
+        gradient: Matrix3<f64>,
+    },
+    // acceleration * sin(2 pi frequency time)
+    Oscillating {
+        acceleration: Vector3<f64>,
+        frequency: f64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RollingResistance {
+    ConstantTorque,
+    SpringDashpot,
+}
+
 pub_struct!( ParticleData {
 
     radius: Vec<f64>,
@@ -32,32 +165,440 @@ pub_struct!( ParticleData {
     youngs_mod: Vec<f64>,
     poisson_ratio: Vec<f64>,
     density: Vec<f64>,
+    liquid_volume: Vec<f64>,
+    contact_angle: Vec<f64>,
     position: Vec<Vector3<f64>>,
     velocity: Vec<Vector3<f64>>,
     force: Vec<Vector3<f64>>,
 
-
+    moment_of_inertia: Vec<f64>,
+    angular_velocity: Vec<Vector3<f64>>,
+    torque: Vec<Vector3<f64>>,
 
 
     is_collision: Vec<bool>,
 
+    // Stable identity of each sphere for output, kept through reordering and removal
+    id: Vec<usize>,
+    next_id: usize,
+
     materials: Vec<Material>,
 
+    // Index of each spheres material in materials
     sphere_material: Vec<usize>,
 
-    sphere_material_map: HashMap<String,EffectiveMaterialPreCalc>,
+    // Dense materials.len() x materials.len() table of pair properties
+    interactions: Vec<InteractionPair>,
+
+    contacts: HashMap<(usize, usize), ContactHistory>,
+
+    // Keyed by (sphere index, wall index)
+    wall_contacts: HashMap<(usize, usize), ContactHistory>,
+    // Keyed by (sphere index, mesh index, triangle index)
+    mesh_contacts: HashMap<(usize, usize, usize), ContactHistory>,
+
+    // Keyed by (min index, max index) like contacts
+    bonds: HashMap<(usize, usize), Bond>,
+    broken_bonds: usize,
 
     restitution_coefficient: f64,
     beta: f64,
+    restitution_model: RestitutionModel,
     friction: f64,
+    contact_law: ContactLaw,
+    rolling_resistance: RollingResistance,
+    rolling_damping: f64,
+    cohesion_model: CohesionModel,
+    surface_tension: f64,
+    interaction_range: f64,
     volume_fraction: f64,
 
+    // Gravity and any other fields, summed
+    body_forces: Vec<BodyForce>,
+    // Simulated time over all cycles, for time dependent fields
+    time: f64,
+
 
 });
 
+// Empty, with the defaults the input file overrides
+impl Default for ParticleData {
+    fn default() -> Self {
+        ParticleData {
+            max_radius: 0.0,
+            radius: Vec::<f64>::new(),
+            mass: Vec::<f64>::new(),
+            youngs_mod: Vec::<f64>::new(),
+            poisson_ratio: Vec::<f64>::new(),
+            density: Vec::<f64>::new(),
+            liquid_volume: Vec::<f64>::new(),
+            contact_angle: Vec::<f64>::new(),
+            position: Vec::<Vector3<f64>>::new(),
+            velocity: Vec::<Vector3<f64>>::new(),
+            force: Vec::<Vector3<f64>>::new(),
+            moment_of_inertia: Vec::<f64>::new(),
+            angular_velocity: Vec::<Vector3<f64>>::new(),
+            torque: Vec::<Vector3<f64>>::new(),
+            is_collision: Vec::<bool>::new(),
+            id: Vec::new(),
+            next_id: 0,
+            materials: Vec::<Material>::new(),
+            sphere_material: Vec::<usize>::new(),
+            interactions: Vec::new(),
+            contacts: HashMap::new(),
+            wall_contacts: HashMap::new(),
+            mesh_contacts: HashMap::new(),
+            bonds: HashMap::new(),
+            broken_bonds: 0,
+            restitution_coefficient: 0.95,
+            beta: 0.0,
+            restitution_model: RestitutionModel::Constant,
+            friction: 0.1,
+            contact_law: ContactLaw::HertzMindlin,
+            rolling_resistance: RollingResistance::SpringDashpot,
+            rolling_damping: 0.3,
+            cohesion_model: CohesionModel::None,
+            surface_tension: 0.0,
+            interaction_range: 0.0,
+            volume_fraction: 0.0,
+            body_forces: Vec::new(),
+            time: 0.0,
+        }
+    }
+}
+
+impl ParticleData {
+    pub fn interaction(&self, i: usize, j: usize) -> &InteractionPair {
+        self.material_interaction(i, self.sphere_material[j])
+    }
+
+    // Pair properties of sphere i against anything made of the material at material_index
+    pub fn material_interaction(&self, i: usize, material_index: usize) -> &InteractionPair {
+        &self.interactions[self.sphere_material[i] * self.materials.len() + material_index]
+    }
+
+    // Total acceleration of sphere i from the body force fields at the current time
+    pub fn body_acceleration(&self, i: usize) -> Vector3<f64> {
+        let mut acceleration = Vector3::zeros();
+        for body_force in &self.body_forces {
+            acceleration += match body_force {
+                BodyForce::Uniform { acceleration } => *acceleration,
+                BodyForce::Gradient {
+                    acceleration,
+                    gradient,
+                } => acceleration + gradient * self.position[i],
+                BodyForce::Oscillating {
+                    acceleration,
+                    frequency,
+                } => acceleration * (2.0 * std::f64::consts::PI * frequency * self.time).sin(),
+            };
+        }
+        acceleration
+    }
+
+    // Appends a sphere of the given material, every per particle array must be pushed here
+    pub fn push_particle(
+        &mut self,
+        material: &Material,
+        position: Vector3<f64>,
+        velocity: Vector3<f64>,
+    ) {
+        let material_index = self
+            .materials
+            .iter()
+            .position(|m| m.id == material.id)
+            .unwrap();
+        self.sphere_material.push(material_index);
+
+        self.radius.push(material.radius);
+
+        self.mass.push(material.mass);
+        self.moment_of_inertia.push(material.moment_of_inertia);
+        self.density.push(material.density);
+        self.youngs_mod.push(material.youngs_mod);
+        self.poisson_ratio.push(material.poisson_ratio);
+        self.liquid_volume.push(material.liquid_volume);
+        self.contact_angle.push(material.contact_angle);
+
+        self.position.push(position);
+        self.velocity.push(velocity);
+        self.force.push(Vector3::new(0.0, 0.0, 0.0));
+
+        self.angular_velocity.push(Vector3::new(0.0, 0.0, 0.0));
+        self.torque.push(Vector3::new(0.0, 0.0, 0.0));
+
+        self.is_collision.push(false);
+
+        self.id.push(self.next_id);
+        self.next_id += 1;
+    }
+
+    // Removes the spheres at the given indexes, each replaced by the last sphere, every per
+    // particle array must be swapped here. Contacts and bonds of the removed spheres are
+    // dropped and those of the moved spheres rekeyed
+    pub fn remove_particles(&mut self, mut removed: Vec<usize>) {
+        removed.sort_unstable();
+        removed.dedup();
+
+        // Where each sphere ends up, and which original sphere is at each place
+        let mut new_index: Vec<Option<usize>> = (0..self.radius.len()).map(Some).collect();
+        let mut original: Vec<usize> = (0..self.radius.len()).collect();
+
+        // From the highest index down, so the spheres still to remove have not moved
+        for &i in removed.iter().rev() {
+            self.sphere_material.swap_remove(i);
+            self.radius.swap_remove(i);
+            self.mass.swap_remove(i);
+            self.moment_of_inertia.swap_remove(i);
+            self.density.swap_remove(i);
+            self.youngs_mod.swap_remove(i);
+            self.poisson_ratio.swap_remove(i);
+            self.liquid_volume.swap_remove(i);
+            self.contact_angle.swap_remove(i);
+            self.position.swap_remove(i);
+            self.velocity.swap_remove(i);
+            self.force.swap_remove(i);
+            self.angular_velocity.swap_remove(i);
+            self.torque.swap_remove(i);
+            self.is_collision.swap_remove(i);
+            self.id.swap_remove(i);
+
+            new_index[i] = None;
+            original.swap_remove(i);
+            if i < original.len() {
+                new_index[original[i]] = Some(i);
+            }
+        }
+
+        self.remap_indexes(&new_index);
+    }
+
+    // Puts the spheres in the given order, order[k] being the index of the sphere to place at k,
+    // every per particle array must be permuted here
+    pub fn reorder(&mut self, order: &[usize]) {
+        fn permute<T: Clone>(values: &mut Vec<T>, order: &[usize]) {
+            *values = order.iter().map(|&i| values[i].clone()).collect();
+        }
+        permute(&mut self.sphere_material, order);
+        permute(&mut self.radius, order);
+        permute(&mut self.mass, order);
+        permute(&mut self.moment_of_inertia, order);
+        permute(&mut self.density, order);
+        permute(&mut self.youngs_mod, order);
+        permute(&mut self.poisson_ratio, order);
+        permute(&mut self.liquid_volume, order);
+        permute(&mut self.contact_angle, order);
+        permute(&mut self.position, order);
+        permute(&mut self.velocity, order);
+        permute(&mut self.force, order);
+        permute(&mut self.angular_velocity, order);
+        permute(&mut self.torque, order);
+        permute(&mut self.is_collision, order);
+        permute(&mut self.id, order);
+
+        let mut new_index = vec![None; order.len()];
+        for (index, &i) in order.iter().enumerate() {
+            new_index[i] = Some(index);
+        }
+        self.remap_indexes(&new_index);
+    }
+
+    // Rekeys the contacts and bonds to the new index of each sphere, dropping those of spheres
+    // without one. Pair keys stay (min index, max index), flipping the history when the order
+    // changes
+    fn remap_indexes(&mut self, new_index: &[Option<usize>]) {
+        let pair_key = |(a, b): (usize, usize)| match (new_index[a], new_index[b]) {
+            (Some(a), Some(b)) => Some(((a.min(b), a.max(b)), a > b)),
+            _ => None,
+        };
+        self.contacts = std::mem::take(&mut self.contacts)
+            .into_iter()
+            .filter_map(|(key, history)| {
+                let (key, flip) = pair_key(key)?;
+                Some((
+                    key,
+                    if flip {
+                        history.oriented(-1.0)
+                    } else {
+                        history
+                    },
+                ))
+            })
+            .collect();
+        self.bonds = std::mem::take(&mut self.bonds)
+            .into_iter()
+            .filter_map(|(key, bond)| {
+                let (key, flip) = pair_key(key)?;
+                Some((key, if flip { bond.flipped() } else { bond }))
+            })
+            .collect();
+        self.wall_contacts = std::mem::take(&mut self.wall_contacts)
+            .into_iter()
+            .filter_map(|((i, wall), history)| Some(((new_index[i]?, wall), history)))
+            .collect();
+        self.mesh_contacts = std::mem::take(&mut self.mesh_contacts)
+            .into_iter()
+            .filter_map(|((i, mesh, triangle), history)| {
+                Some(((new_index[i]?, mesh, triangle), history))
+            })
+            .collect();
+    }
+}
+
 pub_struct!( ForceData {
     particle_indexes: Vec<Vector2<usize>>,
     force: Vec<Vector3<f64>>,
     del: Vec<Vector3<f64>>,
     forcedata:  Vec<Matrix3<f64>>,
 });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Spheres at distinct positions, with a contact and a bond between every pair and a wall and
+    // mesh contact on every sphere. Each pair load is the separation from the smaller index to
+    // the larger, and each wall or mesh history holds the position of its sphere
+    fn linked_particles(count: usize) -> ParticleData {
+        let material = Material {
+            radius: 0.5,
+            mass: 1.0,
+            moment_of_inertia: 0.1,
+            youngs_mod: 1e9,
+            poisson_ratio: 0.3,
+            density: 2500.0,
+            rolling_friction: 0.0,
+            twisting_friction: 0.0,
+            surface_energy: 0.0,
+            liquid_volume: 0.0,
+            contact_angle: 0.0,
+            yield_pressure: 0.0,
+            id: 1,
+        };
+        let mut p_data = ParticleData::default();
+        p_data.materials.push(material.clone());
+        for i in 0..count {
+            let position = Vector3::new(i as f64, (i * i) as f64, 1.0);
+            p_data.push_particle(&material, position, Vector3::zeros());
+        }
+
+        for i in 0..count {
+            for j in i + 1..count {
+                let separation = p_data.position[j] - p_data.position[i];
+                let history = ContactHistory {
+                    tangential_displacement: separation,
+                    rolling_torque: 2.0 * separation,
+                    twisting_torque: 3.0 * separation,
+                    ..Default::default()
+                };
+                p_data.contacts.insert((i, j), history);
+                p_data.bonds.insert(
+                    (i, j),
+                    Bond {
+                        radius: 0.1,
+                        length: separation.norm(),
+                        normal_stiffness: 1.0,
+                        shear_stiffness: 1.0,
+                        tensile_strength: 1.0,
+                        shear_strength: 1.0,
+                        shear_force: separation,
+                        bending_moment: 2.0 * separation,
+                        twisting_moment: 3.0 * separation,
+                    },
+                );
+            }
+            let marker = ContactHistory {
+                tangential_displacement: p_data.position[i],
+                ..Default::default()
+            };
+            p_data.wall_contacts.insert((i, 0), marker.clone());
+            p_data.mesh_contacts.insert((i, 0, 7), marker);
+        }
+        p_data
+    }
+
+    // Every key names the spheres its history was made for, through their ids, and the pair
+    // loads still point from the smaller index to the larger
+    fn assert_linked(p_data: &ParticleData, ids: &[usize]) {
+        let mut expected: Vec<(usize, usize)> = Vec::new();
+        for (a, &id_a) in ids.iter().enumerate() {
+            for &id_b in &ids[a + 1..] {
+                expected.push((id_a.min(id_b), id_a.max(id_b)));
+            }
+        }
+        expected.sort_unstable();
+
+        let id_pairs = |keys: Vec<(usize, usize)>| {
+            let mut pairs: Vec<(usize, usize)> = keys
+                .into_iter()
+                .map(|(i, j)| {
+                    assert!(i < j);
+                    let (id_i, id_j) = (p_data.id[i], p_data.id[j]);
+                    (id_i.min(id_j), id_i.max(id_j))
+                })
+                .collect();
+            pairs.sort_unstable();
+            pairs
+        };
+        assert_eq!(
+            id_pairs(p_data.contacts.keys().copied().collect()),
+            expected
+        );
+        assert_eq!(id_pairs(p_data.bonds.keys().copied().collect()), expected);
+
+        for (&(i, j), history) in &p_data.contacts {
+            let separation = p_data.position[j] - p_data.position[i];
+            assert_eq!(history.tangential_displacement, separation);
+            assert_eq!(history.rolling_torque, 2.0 * separation);
+            assert_eq!(history.twisting_torque, 3.0 * separation);
+        }
+        for (&(i, j), bond) in &p_data.bonds {
+            let separation = p_data.position[j] - p_data.position[i];
+            assert_eq!(bond.shear_force, separation);
+            assert_eq!(bond.bending_moment, 2.0 * separation);
+            assert_eq!(bond.twisting_moment, 3.0 * separation);
+        }
+
+        assert_eq!(p_data.wall_contacts.len(), ids.len());
+        assert_eq!(p_data.mesh_contacts.len(), ids.len());
+        for (&(i, _), history) in &p_data.wall_contacts {
+            assert_eq!(history.tangential_displacement, p_data.position[i]);
+        }
+        for (&(i, _, _), history) in &p_data.mesh_contacts {
+            assert_eq!(history.tangential_displacement, p_data.position[i]);
+        }
+    }
+
+    #[test]
+    fn removal_keeps_histories_on_their_spheres() {
+        let mut p_data = linked_particles(7);
+        p_data.remove_particles(vec![5, 1, 6, 1]);
+
+        let mut ids = p_data.id.clone();
+        ids.sort_unstable();
+        assert_eq!(ids, [0, 2, 3, 4]);
+        for (i, &id) in p_data.id.iter().enumerate() {
+            assert_eq!(
+                p_data.position[i],
+                Vector3::new(id as f64, (id * id) as f64, 1.0)
+            );
+        }
+        assert_linked(&p_data, &p_data.id);
+    }
+
+    #[test]
+    fn reordering_keeps_histories_on_their_spheres() {
+        let mut p_data = linked_particles(6);
+        let order = [3, 0, 5, 1, 4, 2];
+        p_data.reorder(&order);
+
+        assert_eq!(p_data.id, order);
+        for (i, &id) in p_data.id.iter().enumerate() {
+            assert_eq!(
+                p_data.position[i],
+                Vector3::new(id as f64, (id * id) as f64, 1.0)
+            );
+        }
+        assert_linked(&p_data, &p_data.id);
+    }
+}